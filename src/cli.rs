@@ -0,0 +1,352 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::exit,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    command::{CommandLoop, MyCommand, ReceiveFileOkType, SendFileErrorType, SendFileOkType},
+    connect::{pair_code, ListenerState, MyPeerInfo, MyTcplistener},
+    device,
+    discover::MyDiscovery,
+    file::{FileState, FileStateExtend},
+    tls, MyMessage,
+};
+
+#[derive(Parser, Debug)]
+#[clap(
+    name = "file-net",
+    about = "A file transfer tool based on the network."
+)]
+pub struct Opt {
+    /// Run without window. If not provided, the window is opened.
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+    /// Pairing code. Random and printed when listening if not provided,
+    /// only needed when connecting to a device not trusted yet.
+    #[arg(long, global = true)]
+    pub code: Option<String>,
+    /// Name this device is shown with to others, kept for the next runs.
+    #[arg(long, global = true)]
+    pub name: Option<String>,
+    /// Limit of sending in KiB/s, 0 for none. The one set in the window if not provided.
+    #[arg(long, global = true)]
+    pub upload_limit: Option<u64>,
+    /// Limit of receiving in KiB/s, 0 for none. The one set in the window if not provided.
+    #[arg(long, global = true)]
+    pub download_limit: Option<u64>,
+    /// Smallest block size of sent files in KiB, kept for the next runs.
+    #[arg(long, global = true)]
+    pub min_block_size: Option<usize>,
+    /// Largest block size of sent files in KiB, kept for the next runs.
+    #[arg(long, global = true)]
+    pub max_block_size: Option<usize>,
+}
+
+impl Opt {
+    /// (upload, download) in bytes per second
+    pub fn limits(&self) -> (u64, u64) {
+        let me = device::device();
+        (
+            self.upload_limit.map_or(me.upload_limit, |l| l * 1024),
+            self.download_limit.map_or(me.download_limit, |l| l * 1024),
+        )
+    }
+
+    /// Save the block sizes given, the others stay as they were.
+    pub fn save_block_sizes(&self) {
+        if self.min_block_size.is_none() && self.max_block_size.is_none() {
+            return;
+        }
+        let me = device::device();
+        device::set_block_sizes(
+            self.min_block_size.map_or(me.min_block_size, |s| s * 1024),
+            self.max_block_size.map_or(me.max_block_size, |s| s * 1024),
+        );
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Listen on ADDR (ip:port or [ipv6%scope]:port, port 0 for any) and receive files from the peer.
+    Listen {
+        addr: String,
+        /// The folder received files are saved to, the one chosen in the window if not provided.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+    },
+    /// Connect to a peer listening on ADDR (host:port, ip:port or [ipv6%scope]:port) and receive files from it.
+    Connect {
+        addr: String,
+        /// The folder received files are saved to, the one chosen in the window if not provided.
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+    },
+    /// Send files or folders to a peer, and exit when all are sent.
+    Send {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        #[command(flatten)]
+        peer: PeerOpt,
+    },
+    /// Receive files from a peer into a folder.
+    Receive {
+        /// The folder received files are saved to.
+        #[arg(long)]
+        dir: PathBuf,
+        #[command(flatten)]
+        peer: PeerOpt,
+    },
+}
+
+#[derive(Args, Debug)]
+#[group(required = true, multiple = false)]
+pub struct PeerOpt {
+    /// Listen on ip:port for the peer.
+    #[arg(short, long)]
+    listen: Option<String>,
+    /// Connect to the peer listening on ip:port.
+    #[arg(short, long)]
+    connect: Option<String>,
+}
+impl PeerOpt {
+    /// (address, is host)
+    fn addr(self) -> (String, bool) {
+        match (self.listen, self.connect) {
+            (Some(addr), _) => (addr, true),
+            (None, Some(addr)) => (addr, false),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+/// `limits` are (upload, download) in bytes per second, see `Opt::limits`
+pub fn run(command: CliCommand, code: Option<String>, limits: (u64, u64)) {
+    match command {
+        CliCommand::Listen { addr, dir } => {
+            let dir = dir.unwrap_or_else(device::download_dir);
+            receive(&addr, true, dir, code, limits)
+        }
+        CliCommand::Connect { addr, dir } => {
+            let dir = dir.unwrap_or_else(device::download_dir);
+            receive(&addr, false, dir, code, limits)
+        }
+        CliCommand::Receive { dir, peer } => {
+            let (addr, host) = peer.addr();
+            receive(&addr, host, dir, code, limits)
+        }
+        CliCommand::Send { paths, peer } => {
+            let (addr, host) = peer.addr();
+            send(&addr, host, paths, code, limits)
+        }
+    }
+}
+
+fn receive(addr: &str, host: bool, dir: PathBuf, code: Option<String>, limits: (u64, u64)) {
+    let (sc, rm, _) = start(addr, host, dir, code, limits);
+    println!("Ready to receive files.");
+    while let Ok(msg) = rm.recv() {
+        match msg {
+            // other clients may still connect
+            MyMessage::ConnectInterrupt(_) if host => println!("Connection lost."),
+            msg => print_message(&msg),
+        }
+    }
+    drop(sc);
+}
+
+fn send(addr: &str, host: bool, paths: Vec<PathBuf>, code: Option<String>, limits: (u64, u64)) {
+    let mut files = vec![];
+    for path in paths {
+        match FileState::from_path(&path) {
+            Ok(f) => files.push(FileStateExtend {
+                f,
+                is_selected: true,
+            }),
+            Err(e) => fail(&format!("Cannot send {:?}: {e}", path)),
+        }
+    }
+    let (sc, rm, peer) = start(addr, host, device::download_dir(), code, limits);
+    let mut left = files.len();
+    let mut failed = 0;
+    sc.send(MyCommand::SendFiles(peer.id, files)).unwrap();
+    while left > 0 {
+        let Ok(msg) = rm.recv() else {
+            break;
+        };
+        print_message(&msg);
+        match msg {
+            MyMessage::SendFile(_, Ok(SendFileOkType::SendDone(_))) => left -= 1,
+            MyMessage::SendFile(
+                _,
+                Err(
+                    SendFileErrorType::CannotReadFile
+                    | SendFileErrorType::Rejected
+                    | SendFileErrorType::NoStream
+                    | SendFileErrorType::ChecksumMismatch,
+                ),
+            )
+            | MyMessage::CannotSend(..) => {
+                left -= 1;
+                failed += 1;
+            }
+            _ => (),
+        }
+    }
+    if failed > 0 {
+        fail(&format!("{failed} files not sent."));
+    }
+    println!("All files sent.");
+}
+
+/// Connect to the peer, and run the command loop on the connection.
+/// Return the peer too, to send files to it.
+/// A listener keeps accepting clients afterwards, each in its own session.
+fn start(
+    addr: &str,
+    host: bool,
+    dir: PathBuf,
+    code: Option<String>,
+    (upload, download): (u64, u64),
+) -> (Sender<MyCommand>, Receiver<MyMessage>, MyPeerInfo) {
+    let mut ls = MyTcplistener::NULL;
+    if !ls.set_addr(addr) {
+        fail(&format!("Invalid address {addr}"));
+    }
+    ls.code = match code {
+        Some(code) => code,
+        None if host => {
+            let code = pair_code();
+            println!("Pairing code: {code}");
+            code
+        }
+        None => String::new(),
+    };
+    let me = device::device();
+    println!("Device: {} ({})", me.name, me.id);
+    println!(
+        "Certificate: {}",
+        tls::fingerprint_text(&tls::my_fingerprint())
+    );
+    ls.state = ListenerState::TOLISTEN;
+    let mut announced = false;
+    let discovery = MyDiscovery::new();
+    if host {
+        discovery.run();
+    }
+    loop {
+        let ready = if host {
+            ls.handle_listener()
+        } else {
+            ls.handle_connector()
+        };
+        if ready {
+            break;
+        }
+        match ls.state {
+            ListenerState::LISTENING if host && !announced => {
+                println!("Listening on {}, waiting for the peer...", ls.to_string());
+                discovery.set_adverts(ls.addr().into_iter().collect());
+                announced = true;
+            }
+            ListenerState::LISTENING => (),
+            _ => fail(&format!("Cannot connect with {}", ls.to_string())),
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    discovery.set_adverts(vec![]);
+    let (sc, rc) = mpsc::channel::<MyCommand>();
+    let (sm, rm) = mpsc::channel::<MyMessage>();
+    let (streams, ts) = ls.get_tls();
+    let peer = ls.device.clone().unwrap_or_default();
+    let cmd = match (streams, ts) {
+        (Some(streams), Some(ts)) if host => MyCommand::AcceptListener(streams, ts, peer.clone()),
+        (None, Some(ts)) if !host => MyCommand::AcceptConnector(ts, peer.clone()),
+        _ if ls.error.contains("Pairing code needed") => fail(&format!(
+            "Cannot connect with {}: {}, pass it with --code",
+            ls.to_string(),
+            ls.error
+        )),
+        _ => fail(&format!(
+            "Cannot connect with {}: {}",
+            ls.to_string(),
+            ls.error
+        )),
+    };
+    let addr = ls.peer.map_or_else(|| ls.to_string(), |p| p.to_string());
+    println!("Connected with {} ({}) at {addr}.", peer.name, peer.id);
+    if let Some(warning) = &peer.warning {
+        println!("Warning: {warning}, paired again with the code.");
+    }
+    if let Some(fp) = &ls.fingerprint {
+        println!("Peer certificate: {}", tls::fingerprint_text(fp));
+    }
+    let (min_block, max_block) = device::block_sizes();
+    CommandLoop::new(None, sm, sc.clone(), rc)
+        .with_download_dir(dir)
+        .with_limits(upload, download)
+        .with_block_sizes(min_block, max_block)
+        // running the command is the consent
+        .with_auto_accept(true)
+        .run();
+    sc.send(cmd).unwrap();
+    if host {
+        // the listener also routes the data streams, so it lives as long as the process
+        let sc = sc.clone();
+        thread::spawn(move || loop {
+            if ls.handle_listener() {
+                if let (Some(streams), Some(ts)) = ls.get_tls() {
+                    let peer = ls.device.clone().unwrap_or_default();
+                    let addr = ls.peer.map_or_else(|| ls.to_string(), |p| p.to_string());
+                    println!("Connected with {} ({}) at {addr}.", peer.name, peer.id);
+                    if let Some(warning) = &peer.warning {
+                        println!("Warning: {warning}, paired again with the code.");
+                    }
+                    sc.send(MyCommand::AcceptListener(streams, ts, peer))
+                        .unwrap();
+                }
+            }
+            if ls.state != ListenerState::LISTENING {
+                println!("Stop listening: {}", ls.error);
+                return;
+            }
+            thread::sleep(Duration::from_millis(100));
+        });
+    }
+    (sc, rm, peer)
+}
+
+fn print_message(msg: &MyMessage) {
+    match msg {
+        MyMessage::Text(t) => println!("{t}"),
+        MyMessage::ConnectInterrupt(_) => fail("Connection lost."),
+        MyMessage::Sessions(_) | MyMessage::Offers(_) => (),
+        MyMessage::SendFile(id, Ok(SendFileOkType::SendProgress(p))) => {
+            print!("\rSending file {id}: {:.1}%      ", p * 100.0);
+            std::io::stdout().flush().unwrap();
+        }
+        MyMessage::SendFile(id, Ok(SendFileOkType::SendDone(stats))) => {
+            println!("\rSending file {id}: Done, {stats}        ");
+        }
+        MyMessage::SendFile(id, Err(e)) => println!("\rSending file {id}: {:?}", e),
+        MyMessage::CannotSend(name, e) => println!("\rCannot send {name}: {e}"),
+        MyMessage::ReceiveFile(id, Ok(ReceiveFileOkType::ReceiveProgress(p))) => {
+            print!("\rReceiving file {id}: {:.1}%      ", p * 100.0);
+            std::io::stdout().flush().unwrap();
+        }
+        MyMessage::ReceiveFile(id, Ok(ReceiveFileOkType::ReceiveDone)) => {
+            println!("\rReceiving file {id}: Done        ");
+        }
+        MyMessage::ReceiveFile(id, Err(e)) => println!("\rReceiving file {id}: {:?}", e),
+    }
+}
+
+fn fail(info: &str) -> ! {
+    eprintln!("{info}");
+    exit(1);
+}
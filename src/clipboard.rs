@@ -0,0 +1,227 @@
+use std::{
+    borrow::Cow,
+    error::Error,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use arboard::{Clipboard, ImageData};
+use sha2::{Digest, Sha256};
+
+use crate::{command::MyCommand, device};
+
+/// how often the clipboard is checked in automatic mode
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+/// longer text is not sent, it must fit in a frame
+pub const MAX_CLIPBOARD_TEXT: usize = 4 << 20;
+/// larger images are sent as a png file instead, they must fit in a frame
+pub const MAX_CLIPBOARD_IMAGE: usize = 8 << 20;
+/// where images too large for the clipboard channel are saved to be sent, in the config dir
+const IMAGE_DIR: &str = "clipboard";
+
+/// What can be copied and sent to a peer.
+#[derive(Debug, Clone)]
+pub enum MyClip {
+    Text(String),
+    /// png encoded
+    Image(Vec<u8>),
+}
+
+struct ClipboardState {
+    /// opened on first use, kept so that what we set stays available
+    board: Option<Clipboard>,
+    /// sha256 of what was last sent or received, never sent again by the automatic mode,
+    /// so that two peers do not send the same clip back and forth
+    last: Option<[u8; 32]>,
+}
+
+/// The system clipboard, shared between peers.
+#[derive(Clone)]
+pub struct MyClipboard {
+    state: Arc<Mutex<ClipboardState>>,
+    /// send every new copied clip to the peers
+    auto: Arc<AtomicBool>,
+}
+
+impl MyClipboard {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(ClipboardState {
+                board: None,
+                last: None,
+            })),
+            auto: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The text or image on the clipboard, to send it.
+    pub fn content(&self) -> Result<MyClip, String> {
+        let mut state = self.state.lock().unwrap();
+        // nothing is skipped without a digest to compare with
+        let (clip, digest) = Self::read(&mut state, None)?.unwrap();
+        state.last = Some(digest);
+        Ok(clip)
+    }
+
+    /// The clip on the clipboard, if copied since the last one sent or received.
+    pub fn changed(&self) -> Option<MyClip> {
+        let mut state = self.state.lock().unwrap();
+        let last = state.last;
+        let (clip, digest) = Self::read(&mut state, last).ok()??;
+        state.last = Some(digest);
+        Some(clip)
+    }
+
+    /// Put a clip received from a peer on the clipboard.
+    /// Return false if it is already there.
+    pub fn set(&self, clip: MyClip) -> Result<bool, String> {
+        let mut state = self.state.lock().unwrap();
+        let digest = match clip {
+            MyClip::Text(text) => {
+                let digest = text_digest(&text);
+                if state.last == Some(digest) {
+                    return Ok(false);
+                }
+                Self::board(&mut state)?
+                    .set_text(text)
+                    .map_err(|e| e.to_string())?;
+                digest
+            }
+            MyClip::Image(png) => {
+                let image = decode_png(&png).map_err(|e| e.to_string())?;
+                let digest = image_digest(&image);
+                if state.last == Some(digest) {
+                    return Ok(false);
+                }
+                Self::board(&mut state)?
+                    .set_image(image)
+                    .map_err(|e| e.to_string())?;
+                digest
+            }
+        };
+        state.last = Some(digest);
+        Ok(true)
+    }
+
+    pub fn set_auto(&self, auto: bool) {
+        if auto {
+            // only what is copied from now on
+            let _ = self.content();
+        }
+        self.auto.store(auto, Ordering::SeqCst);
+    }
+
+    /// Check the clipboard in the background, and ask the command loop
+    /// to send what is copied while in automatic mode.
+    pub fn watch(&self, cmd: Sender<MyCommand>) {
+        let clipboard = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(WATCH_INTERVAL);
+            if !clipboard.auto.load(Ordering::SeqCst) {
+                continue;
+            }
+            if let Some(clip) = clipboard.changed() {
+                if cmd.send(MyCommand::SendClipboard(Some(clip))).is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Text if any, else the image, with its digest.
+    /// `None` if the digest is `skip`, then images are not encoded.
+    fn read(
+        state: &mut ClipboardState,
+        skip: Option<[u8; 32]>,
+    ) -> Result<Option<(MyClip, [u8; 32])>, String> {
+        let board = Self::board(state)?;
+        if let Some(text) = board.get_text().ok().filter(|t| !t.is_empty()) {
+            let digest = text_digest(&text);
+            return Ok((skip != Some(digest)).then_some((MyClip::Text(text), digest)));
+        }
+        let image = board.get_image().map_err(|e| e.to_string())?;
+        let digest = image_digest(&image);
+        if skip == Some(digest) {
+            return Ok(None);
+        }
+        let png = encode_png(&image).map_err(|e| e.to_string())?;
+        Ok(Some((MyClip::Image(png), digest)))
+    }
+
+    fn board(state: &mut ClipboardState) -> Result<&mut Clipboard, String> {
+        if state.board.is_none() {
+            state.board = Some(Clipboard::new().map_err(|e| e.to_string())?);
+        }
+        Ok(state.board.as_mut().unwrap())
+    }
+}
+
+fn text_digest(text: &str) -> [u8; 32] {
+    Sha256::digest(text.as_bytes()).into()
+}
+
+fn image_digest(image: &ImageData) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update((image.width as u64).to_le_bytes());
+    hasher.update((image.height as u64).to_le_bytes());
+    hasher.update(&image.bytes);
+    hasher.finalize().into()
+}
+
+/// Save a copied image as a png file, to send it like other files.
+/// It is deleted once sent, see `CommandLoop::drop_clipboard_file`.
+pub fn save_image(png: &[u8]) -> Result<PathBuf, Box<dyn Error>> {
+    // two images copied within a second must not overwrite each other
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos();
+    let dir = device::config_dir().join(IMAGE_DIR);
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("clipboard-{nanos}.png"));
+    fs::write(&path, png)?;
+    Ok(path)
+}
+
+/// rgba pixels to png
+pub fn encode_png(image: &ImageData) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut png = vec![];
+    let mut encoder = png::Encoder::new(&mut png, image.width as u32, image.height as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::Fast);
+    encoder.write_header()?.write_image_data(&image.bytes)?;
+    Ok(png)
+}
+
+/// png to rgba pixels
+pub fn decode_png(png: &[u8]) -> Result<ImageData<'static>, Box<dyn Error>> {
+    let mut decoder = png::Decoder::new(png);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf)?;
+    buf.truncate(info.buffer_size());
+    let bytes = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf
+            .chunks(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => buf
+            .chunks(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|g| [*g, *g, *g, 255]).collect(),
+        t => return Err(format!("Unsupported png color {t:?}").into()),
+    };
+    Ok(ImageData {
+        width: info.width as usize,
+        height: info.height as usize,
+        bytes: Cow::Owned(bytes),
+    })
+}
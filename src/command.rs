@@ -1,69 +1,110 @@
 use std::{
-    collections::HashMap,
-    net::{TcpListener, TcpStream},
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fs,
+    path::PathBuf,
     sync::{
-        atomic::AtomicUsize,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{self, Receiver, Sender},
         Arc, Mutex,
     },
     thread::{self, JoinHandle},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    clipboard::{self, MyClip, MyClipboard, MAX_CLIPBOARD_IMAGE, MAX_CLIPBOARD_TEXT},
     connect::connect_loop,
     file::{FileBlock, FileBlocks, FileState, FileStateExtend},
+    limit::MyRateLimit,
+    size_text,
+    tls::MyStream,
+    window::MyWindow,
     MyMessage,
 };
 
 use crate::connect;
-use connect::{tcp_read, tcp_write, TCPSignal};
+use connect::{
+    read_any_frame, tcp_read, tcp_write, write_frame, FrameError, FrameType, MyPeerInfo, MyStreams,
+    TCPSignal, CAP_CHECKSUM, CAP_CLIPBOARD, CAP_CLIPBOARD_IMAGE, CAP_DEFLATE,
+};
 
 #[derive(Debug)]
 pub enum MyCommand {
     TrayShow,
     TrayHide,
-    AcceptListener(TcpListener, TcpStream),
-    AcceptConnector(TcpStream),
+    /// data streams routed by the listener, control stream, peer
+    AcceptListener(MyStreams, MyStream, MyPeerInfo),
+    AcceptConnector(MyStream, MyPeerInfo),
 
-    AddTcpSender(TcpStream),
-    AddTcpReceiver(TcpStream),
+    /// Commands from a connection are tagged with the peer's device id.
+    AddTcpSender(String, MyStream),
+    AddTcpReceiver(String, MyStream),
 
-    SendFiles(Vec<FileStateExtend>),
+    /// peer id, files to send to it
+    SendFiles(String, Vec<FileStateExtend>),
+    /// peer id, file, its blocks read on a worker thread, or why they could not be
+    FileLoaded(String, FileState, Result<FileBlocks, String>),
+    /// peer id, file id, blocks the receiver is still missing
+    SendRemaining(String, usize, Vec<usize>),
     SendFileError(usize, SendFileErrorType),
     SendFileOk(usize, SendFileOkType),
+    /// peer id, file id, whether the peer found the whole file intact
+    FileReceived(String, usize, bool),
 
-    ReceiveFile(FileState, FileBlocks),
+    /// peer id, file posted by it
+    ReceiveFile(String, FileState, FileBlocks),
+    /// peer id, file id
+    QueryRemaining(String, usize),
+    /// offer id, what the user decided
+    AnswerOffer(usize, OfferAnswer),
+    /// peer id, id of the file it does not want
+    FileRejected(String, usize),
     ReceiveFileError(usize, ReceiveFileErrorType),
     ReceiveFileOk(usize, ReceiveFileOkType),
+    /// run id, blocks to be sent again, as the file did not match its digest
+    ReceiveAgain(usize, Vec<usize>),
+
+    /// clip to send to every peer, `None` to read the clipboard now
+    SendClipboard(Option<MyClip>),
+    /// peer id, clip copied on it
+    ReceiveClipboard(String, MyClip),
+    /// send copied clips to the peers automatically
+    AutoClipboard(bool),
+    /// where received files are saved from now on
+    SetDownloadDir(PathBuf),
+    /// bytes per second, 0 for no limit
+    SetLimit(MyLimit, u64),
+    /// bounds of the block size of files sent from now on, in bytes
+    SetBlockSizes(usize, usize),
 
-    ConnectLoopStop,
+    /// peer id, session number
+    ConnectLoopStop(String, usize),
 }
 
 #[derive(Debug)]
 pub enum SendFileErrorType {
     CannotReadFile,
     SendError,
+    /// the receiver does not want the file
+    Rejected,
+    /// every data stream broke and no new one came, sent again after reconnecting
+    NoStream,
+    /// every block arrived, but the receiver found the file not matching its digest
+    ChecksumMismatch,
 }
 #[derive(Debug)]
 pub enum SendFileOkType {
-    SendDone,
+    SendDone(MyTransferStats),
     SendProgress(f32),
 }
-impl SendFileOkType {
-    pub fn is_ok(&self) -> bool {
-        if let SendFileOkType::SendDone = self {
-            true
-        } else {
-            false
-        }
-    }
-}
 
 #[derive(Debug)]
 pub enum ReceiveFileErrorType {
     ReceiveError,
     CannotWriteFile,
+    /// every block was intact, but the whole file does not match the digest
+    ChecksumMismatch,
 }
 #[derive(Debug)]
 pub enum ReceiveFileOkType {
@@ -80,6 +121,79 @@ impl ReceiveFileOkType {
     }
 }
 
+/// What was sent of a file, in this run only when resumed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MyTransferStats {
+    /// data of the file
+    pub bytes: usize,
+    /// block frames written for it, smaller if deflated
+    pub wire: usize,
+}
+impl MyTransferStats {
+    /// how many times smaller the data was on the wire
+    pub fn ratio(&self) -> f32 {
+        if self.wire == 0 {
+            1.0
+        } else {
+            self.bytes as f32 / self.wire as f32
+        }
+    }
+}
+impl std::fmt::Display for MyTransferStats {
+    /// `40.0 MiB as 8.1 MiB (4.9x)`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} as {} ({:.1}x)",
+            size_text(self.bytes),
+            size_text(self.wire),
+            self.ratio()
+        )
+    }
+}
+
+/// What a rate limit applies to
+#[derive(Debug)]
+pub enum MyLimit {
+    /// all sent files
+    Upload,
+    /// all received files
+    Download,
+    /// file id
+    Send(usize),
+    /// run id
+    Receive(usize),
+}
+
+/// A file posted by a peer, waiting for the user to accept it.
+#[derive(Debug, Clone)]
+pub struct MyOffer {
+    pub id: usize,
+    /// device id of the sender
+    pub peer: String,
+    pub peer_name: String,
+    pub name: String,
+    pub size: usize,
+    pub is_folder: bool,
+}
+
+#[derive(Debug)]
+pub enum OfferAnswer {
+    Accept,
+    Reject,
+    /// accept this and every later file of the same peer, until the app is closed
+    AcceptAll,
+}
+
+/// An offer with what is needed to receive the file.
+struct MyPendingOffer {
+    offer: MyOffer,
+    file: FileState,
+    blocks: FileBlocks,
+    /// whether the sender asked for the remaining blocks, answered once accepted
+    queried: bool,
+}
+
 pub enum MyConnectCommand {
     ToStop,
     AddTcpStream,
@@ -91,104 +205,399 @@ impl From<TCPSignal> for MyConnectCommand {
     }
 }
 
+/// blocks written to a stream before waiting for their answers
+const SEND_WINDOW: usize = 8;
+/// data streams opened to each peer, the blocks of a file are sent on all free ones
+const DATA_STREAMS: usize = 4;
+/// how often the sending of a file looks for free streams
+const SCHEDULE_INTERVAL: Duration = Duration::from_millis(20);
+/// blocks a file is split into at least, if the block size allows it,
+/// so that big files do not take too many round trips
+const TARGET_BLOCKS: usize = 1024;
+/// the throughput is measured over this long, longer pauses are not counted
+const STATS_PERIOD: Duration = Duration::from_secs(1);
+/// how long a file waits for new data streams once all of its streams broke
+const STREAM_TIMEOUT: Duration = Duration::from_secs(30);
+/// times a file is received before giving up if it does not match its digest
+const RECEIVE_ATTEMPTS: usize = 2;
+
+struct LinkStats {
+    /// shortest time from writing a block to its answer
+    rtt: Option<Duration>,
+    /// bytes per second acknowledged, smoothed
+    throughput: Option<f64>,
+    /// acknowledged since `since`
+    bytes: usize,
+    since: Instant,
+    /// when the last answer came
+    last: Instant,
+}
+
+/// What is measured of the data streams to a peer, to choose block sizes.
+#[derive(Clone)]
+struct MyLinkStats {
+    stats: Arc<Mutex<LinkStats>>,
+}
+
+impl MyLinkStats {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            stats: Arc::new(Mutex::new(LinkStats {
+                rtt: None,
+                throughput: None,
+                bytes: 0,
+                since: now,
+                last: now,
+            })),
+        }
+    }
+
+    /// A block of `bytes` written at `sent_at` is acknowledged now.
+    fn record(&self, bytes: usize, sent_at: Instant) {
+        let mut stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        let rtt = now - sent_at;
+        stats.rtt = Some(stats.rtt.map_or(rtt, |r| r.min(rtt)));
+        if now - stats.last > STATS_PERIOD {
+            // idle meanwhile
+            stats.since = sent_at;
+            stats.bytes = 0;
+        }
+        stats.last = now;
+        stats.bytes += bytes;
+        let elapsed = now - stats.since;
+        if elapsed >= STATS_PERIOD {
+            let rate = stats.bytes as f64 / elapsed.as_secs_f64();
+            stats.throughput = Some(stats.throughput.map_or(rate, |t| (t + rate) / 2.0));
+            stats.since = now;
+            stats.bytes = 0;
+        }
+    }
+
+    /// bytes on the way during a round trip, 0 until measured
+    fn in_flight(&self) -> usize {
+        let stats = self.stats.lock().unwrap();
+        match (stats.throughput, stats.rtt) {
+            (Some(t), Some(rtt)) => (t * rtt.as_secs_f64()) as usize,
+            _ => 0,
+        }
+    }
+}
+
+/// Blocks of a file shared by the streams sending it.
+#[derive(Default)]
+struct SendQueue {
+    /// not sent yet, or to send again
+    todo: VecDeque<usize>,
+    /// sent and waiting for the answer
+    in_flight: usize,
+    /// acknowledged, of the whole file
+    done: usize,
+    /// streams sending the file
+    workers: usize,
+    /// of the acknowledged blocks
+    stats: MyTransferStats,
+    /// a block could not be read, nothing more is sent
+    failed: bool,
+}
+
+impl SendQueue {
+    fn take(&mut self) -> Option<usize> {
+        if self.failed {
+            return None;
+        }
+        let pos = self.todo.pop_front()?;
+        self.in_flight += 1;
+        Some(pos)
+    }
+    /// send `pos` again, before the others
+    fn retry(&mut self, pos: usize) {
+        self.in_flight -= 1;
+        self.todo.push_front(pos);
+    }
+    /// `block` is acknowledged, return the blocks acknowledged so far
+    fn done(&mut self, block: &SentBlock) -> usize {
+        self.in_flight -= 1;
+        self.done += 1;
+        self.stats.bytes += block.bytes;
+        self.stats.wire += block.wire;
+        self.done
+    }
+    fn finished(&self) -> bool {
+        self.todo.is_empty() && self.in_flight == 0
+    }
+    /// A stream sending the file broke, the blocks on it are sent first by the others.
+    fn broken(&mut self, sent: VecDeque<SentBlock>) {
+        for block in sent.into_iter().rev() {
+            self.retry(block.pos);
+        }
+        self.workers -= 1;
+    }
+}
+
+/// A block waiting for its answer
+struct SentBlock {
+    pos: usize,
+    /// data of the file
+    bytes: usize,
+    /// length of the frame
+    wire: usize,
+    at: Instant,
+}
+
+/// What every stream sending a file needs.
+#[derive(Clone)]
+struct SendJob {
+    fb: Arc<FileBlocks>,
+    queue: Arc<Mutex<SendQueue>>,
+    stop: Arc<AtomicBool>,
+    limit: MyRateLimit,
+}
+
 struct MyBlockSender {
-    pub streams: Arc<Mutex<Vec<TcpStream>>>,
+    pub streams: Arc<Mutex<Vec<MyStream>>>,
     pub msg: Sender<MyCommand>,
+    /// the connect loop of the session, which opens the streams
+    connect: Sender<MyConnectCommand>,
     counter: Arc<AtomicUsize>,
+    /// shared by all sessions
+    upload: MyRateLimit,
+    /// streams asked for and not broken, free or in use
+    opened: Arc<AtomicUsize>,
+    link: MyLinkStats,
+    /// whether the peer takes deflated blocks, see `CAP_DEFLATE`
+    deflate: Arc<AtomicBool>,
+    /// (min, max) block size, shared by all sessions
+    block_sizes: Arc<Mutex<(usize, usize)>>,
 }
 
 impl MyBlockSender {
-    fn new(msg: Sender<MyCommand>) -> Self {
+    /// `counter` is shared by all sessions, so that run ids are unique
+    fn new(
+        msg: Sender<MyCommand>,
+        connect: Sender<MyConnectCommand>,
+        counter: Arc<AtomicUsize>,
+        upload: MyRateLimit,
+        block_sizes: Arc<Mutex<(usize, usize)>>,
+    ) -> Self {
         Self {
             streams: Arc::new(Mutex::new(Vec::new())),
             msg,
-            counter: Arc::new(AtomicUsize::new(1)),
+            connect,
+            counter,
+            upload,
+            opened: Arc::new(AtomicUsize::new(0)),
+            link: MyLinkStats::new(),
+            deflate: Arc::new(AtomicBool::new(false)),
+            block_sizes,
         }
     }
-    fn push(&mut self, ts: TcpStream) {
+    fn push(&mut self, ts: MyStream) {
         self.streams.lock().unwrap().push(ts)
     }
-    fn pop(&mut self) -> Option<TcpStream> {
+    fn pop(&mut self) -> Option<MyStream> {
         self.streams.lock().unwrap().pop()
     }
-    /// return a run id
-    pub fn send(&mut self, file: FileStateExtend) -> FileBlocks {
+    /// Ask the connect loop for the data streams missing up to `count`,
+    /// return false if it is stopped.
+    fn open_streams(&self, count: usize) -> bool {
+        let more = |n| (n < count).then_some(n + 1);
+        while self
+            .opened
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, more)
+            .is_ok()
+        {
+            if self.connect.send(MyConnectCommand::AddTcpStream).is_err() {
+                self.opened.fetch_sub(1, Ordering::SeqCst);
+                return false;
+            }
+        }
+        true
+    }
+    /// Open the file to send with a new run id.
+    pub fn load(&mut self, file: &FileState) -> Result<FileBlocks, Box<dyn Error>> {
+        let mut fb = FileBlocks::new(self.next_id());
+        if file.is_folder {
+            fb.load_folder(&file.get_path())?;
+        } else {
+            fb.load(&file.get_path())?;
+        }
+        fb.set_block_size(self.block_size(fb.file_size));
+        printlnl!("FILE;; {:#?}", fb.info());
+        Ok(fb)
+    }
+    /// Block size for a file of `file_size` within the configured bounds: large enough
+    /// for a big file not to take more than `TARGET_BLOCKS` blocks, and for a window
+    /// of blocks to cover what the link carries in a round trip.
+    fn block_size(&self, file_size: usize) -> usize {
+        let (min, max) = *self.block_sizes.lock().unwrap();
+        let by_file = file_size / TARGET_BLOCKS;
+        // still a window of blocks in the file, so that it is sent in parallel
+        let by_link = (self.link.in_flight() / SEND_WINDOW).min(file_size / SEND_WINDOW);
+        by_file.max(by_link).next_power_of_two().clamp(min, max)
+    }
+    /// Send the remaining blocks of `fb` within `limit` of the file,
+    /// return the flag to stop sending.
+    /// Every free stream takes part while there are more blocks than the busy ones
+    /// can keep in flight, so that the throughput scales with the streams.
+    pub fn send(&mut self, fb: FileBlocks, limit: MyRateLimit) -> Arc<AtomicBool> {
         let mut slf = self.clone();
-        let id = self.next_id();
-        let data = match file.f.get() {
-            Ok(data) => data,
-            Err(e) => {
-                println!("Read file error: {e}");
-                self.msg
-                    .send(MyCommand::SendFileError(
-                        id,
-                        SendFileErrorType::CannotReadFile,
-                    ))
+        let id = fb.id;
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut todo: Vec<usize> = fb.remaining.iter().copied().collect();
+        todo.sort_unstable();
+        let queue = SendQueue {
+            done: fb.block_num - todo.len(),
+            todo: todo.into(),
+            ..Default::default()
+        };
+        let job = SendJob {
+            fb: Arc::new(fb),
+            queue: Arc::new(Mutex::new(queue)),
+            stop: Arc::clone(&stop),
+            limit,
+        };
+        // since when no stream is left to send on
+        let mut waiting: Option<Instant> = None;
+        thread::spawn(move || loop {
+            if job.stop.load(Ordering::SeqCst) {
+                println!("Stop sending file {id}");
+                return;
+            }
+            let (finished, failed, workers, wanted, stats) = {
+                let q = job.queue.lock().unwrap();
+                let wanted = q.todo.len() > q.workers * SEND_WINDOW;
+                (q.finished(), q.failed, q.workers, wanted, q.stats)
+            };
+            if failed {
+                return;
+            }
+            if finished {
+                slf.msg
+                    .send(MyCommand::SendFileOk(id, SendFileOkType::SendDone(stats)))
                     .unwrap();
-                return FileBlocks::default();
+                println!("Everything Sent");
+                return;
             }
-        };
-        let mut fb = FileBlocks::new(id);
-        fb.load(data);
-        let res = fb.info();
-        printlnl!("FILE;; {:#?}", res);
-        thread::spawn(move || {
-            let mut pos = 0;
-            while !fb.is_finished() {
-                if let Some(mut ts) = slf.pop() {
-                    // println!("Trying to send file......");
-                    let fdata = fb.get(pos);
-                    let file: FileBlock = (&fdata).into();
-                    printlnl!("Sending File Block Info: {}:{}", file.file_id, file.index);
-                    tcp_write(&mut ts, &fdata).unwrap();
-                    // println!("Send ok. Trying to recv response......");
-                    let recdata = match tcp_read(&mut ts) {
-                        Ok(d) => d,
-                        Err(e) => {
-                            printlnl!("Error {e}");
-                            break;
-                        }
-                    };
-                    let signal: TCPSignal = recdata.into();
-                    println!("Recv ok.");
-                    slf.push(ts);
-
-                    if signal.is_ok() {
+            if wanted {
+                if let Some(ts) = slf.pop() {
+                    waiting = None;
+                    job.queue.lock().unwrap().workers += 1;
+                    let (worker, job) = (slf.clone(), job.clone());
+                    thread::spawn(move || worker.send_blocks(ts, job));
+                    continue;
+                }
+                if workers == 0 {
+                    // the broken streams are opened again, the file fails if none comes
+                    let since = *waiting.get_or_insert_with(Instant::now);
+                    if !slf.open_streams(DATA_STREAMS) || since.elapsed() > STREAM_TIMEOUT {
+                        printlnl!("Send file error: Cannot find tcp stream!");
                         slf.msg
-                            .send(MyCommand::SendFileOk(
+                            .send(MyCommand::SendFileError(id, SendFileErrorType::NoStream))
+                            .unwrap();
+                        return;
+                    }
+                }
+            }
+            thread::sleep(SCHEDULE_INTERVAL);
+        });
+        stop
+    }
+
+    /// Send blocks of the job on `ts`, up to `SEND_WINDOW` of them before reading
+    /// the answers, which come in the same order. The stream is given back once
+    /// nothing is left to send, or dropped if broken.
+    fn send_blocks(mut self, mut ts: MyStream, job: SendJob) {
+        let id = job.fb.id;
+        let mut sent: VecDeque<SentBlock> = VecDeque::new();
+        let deflate = self.deflate.load(Ordering::SeqCst);
+        loop {
+            while sent.len() < SEND_WINDOW && !job.stop.load(Ordering::SeqCst) {
+                let Some(pos) = job.queue.lock().unwrap().take() else {
+                    break;
+                };
+                let block = match job.fb.get(pos) {
+                    Ok(block) => block,
+                    Err(e) => {
+                        printlnl!("Read file block error: {e}");
+                        let mut q = job.queue.lock().unwrap();
+                        q.in_flight -= 1;
+                        q.failed = true;
+                        self.msg
+                            .send(MyCommand::SendFileError(
                                 id,
-                                SendFileOkType::SendProgress(pos as f32 / fb.block_num as f32),
+                                SendFileErrorType::CannotReadFile,
                             ))
                             .unwrap();
-                        fb.done(pos);
-                        pos += 1;
-                    } else {
-                        printlnl!("Send file error: {:?}", signal);
-                        slf.msg
-                            .send(MyCommand::SendFileError(id, SendFileErrorType::SendError))
-                            .unwrap();
-                        continue;
+                        break;
                     }
-                } else {
-                    printlnl!("Send file error: Cannot find tcp stream!");
-                    thread::sleep(Duration::from_millis(1500));
-                    slf.msg
-                        .send(MyCommand::SendFileError(id, SendFileErrorType::SendError))
-                        .unwrap();
-                    continue;
+                };
+                printlnl!("Sending File Block Info: {}:{}", block.file_id, block.index);
+                let (frame, data): (_, Vec<u8>) = match deflate.then(|| block.deflated()) {
+                    Some(Some(deflated)) => (FrameType::DeflatedBlock, (&deflated).into()),
+                    _ => (FrameType::Block, (&block).into()),
+                };
+                job.limit.take(data.len());
+                self.upload.take(data.len());
+                sent.push_back(SentBlock {
+                    pos,
+                    bytes: block.data.len(),
+                    wire: data.len(),
+                    at: Instant::now(),
+                });
+                if let Err(e) = write_frame(&mut ts, frame, &data) {
+                    return self.broken(e, &job, sent);
                 }
             }
-            slf.msg
-                .send(MyCommand::SendFileOk(id, SendFileOkType::SendDone))
-                .unwrap();
-            println!("Everything Sent");
-        });
-        res
+            if sent.is_empty() {
+                break;
+            }
+            let signal: TCPSignal = match tcp_read(&mut ts) {
+                Ok(d) => d.into(),
+                Err(e) => return self.broken(e, &job, sent),
+            };
+            let block = sent.pop_front().unwrap();
+            if let TCPSignal::Nack(_, index) = signal {
+                printlnl!("Block {id}:{index} received corrupt, send again");
+                job.queue.lock().unwrap().retry(block.pos);
+            } else if signal.is_ok() {
+                self.link.record(block.wire, block.at);
+                let done = job.queue.lock().unwrap().done(&block);
+                self.msg
+                    .send(MyCommand::SendFileOk(
+                        id,
+                        SendFileOkType::SendProgress(done as f32 / job.fb.block_num as f32),
+                    ))
+                    .unwrap();
+            } else {
+                printlnl!("Send file error: {:?}", signal);
+                job.queue.lock().unwrap().retry(block.pos);
+                self.msg
+                    .send(MyCommand::SendFileError(id, SendFileErrorType::SendError))
+                    .unwrap();
+            }
+        }
+        job.queue.lock().unwrap().workers -= 1;
+        self.push(ts);
+    }
+
+    /// The stream is broken, the blocks sent on it go back to the queue
+    /// for the other streams, or are sent after reconnecting.
+    fn broken(&self, e: FrameError, job: &SendJob, sent: VecDeque<SentBlock>) {
+        printlnl!("Error {e}");
+        self.opened.fetch_sub(1, Ordering::SeqCst);
+        job.queue.lock().unwrap().broken(sent);
+        self.msg
+            .send(MyCommand::SendFileError(
+                job.fb.id,
+                SendFileErrorType::SendError,
+            ))
+            .unwrap();
     }
 
     fn next_id(&self) -> usize {
-        self.counter
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        self.counter.fetch_add(1, Ordering::SeqCst)
     }
 }
 
@@ -197,60 +606,132 @@ impl Clone for MyBlockSender {
         Self {
             streams: Arc::clone(&self.streams),
             msg: self.msg.clone(),
+            connect: self.connect.clone(),
             counter: self.counter.clone(),
+            upload: self.upload.clone(),
+            opened: self.opened.clone(),
+            link: self.link.clone(),
+            deflate: self.deflate.clone(),
+            block_sizes: self.block_sizes.clone(),
         }
     }
 }
 
-struct MySenderState {}
+struct MySenderState {
+    /// device id of the receiver
+    peer: String,
+    /// file state as posted to the receiver
+    file: FileState,
+    blocks: FileBlocks,
+    /// stop flag of the running send, if any
+    stop: Option<Arc<AtomicBool>>,
+    /// of this file only, kept when resuming
+    limit: MyRateLimit,
+    /// set once every block is acknowledged
+    sent: Option<MyTransferStats>,
+    /// whether the receiver found the file intact, once it has checked
+    intact: Option<bool>,
+}
 impl MySenderState {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(peer: String, file: FileState, blocks: FileBlocks) -> Self {
+        Self {
+            peer,
+            file,
+            blocks,
+            stop: None,
+            limit: MyRateLimit::new(0),
+            sent: None,
+            intact: None,
+        }
+    }
+    fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.store(true, Ordering::SeqCst);
+        }
     }
 }
 
+/// run id, blocks to write, rate limit of the file
+type FileReceiver = (usize, Sender<FileBlock>, MyRateLimit);
+
 struct MyBlockReceiver {
     pub streams: Arc<Mutex<Vec<JoinHandle<()>>>>,
-    /// file id  -->  sender of file id receiver
-    pub allocate_map: Arc<Mutex<HashMap<usize, Sender<FileBlock>>>>,
+    /// file id  -->  the run receiving it, until the run ends
+    pub allocate_map: Arc<Mutex<HashMap<usize, FileReceiver>>>,
     pub msg: Sender<MyCommand>,
     counter: Arc<AtomicUsize>,
+    /// whether the peer sends checksums to verify, see `CAP_CHECKSUM`
+    checksum: Arc<AtomicBool>,
+    /// shared by all sessions
+    download: MyRateLimit,
 }
 
 impl MyBlockReceiver {
-    fn new(msg: Sender<MyCommand>) -> Self {
+    fn new(msg: Sender<MyCommand>, counter: Arc<AtomicUsize>, download: MyRateLimit) -> Self {
         // let (sender, receiver) = std::sync::m::channel();
         // thread::spawn(move||)
         Self {
             streams: Arc::new(Mutex::new(Vec::new())),
             msg,
-            counter: Arc::new(AtomicUsize::new(1)),
+            counter,
             allocate_map: Arc::new(Mutex::new(HashMap::new())),
+            checksum: Arc::new(AtomicBool::new(true)),
+            download,
         }
     }
-    fn push(&mut self, mut ts: TcpStream) {
+    fn push(&mut self, mut ts: MyStream) {
         let map = Arc::clone(&self.allocate_map);
+        let checksum = Arc::clone(&self.checksum);
+        let download = self.download.clone();
         self.streams
             .lock()
             .unwrap()
             .push(thread::spawn(move || loop {
-                match tcp_read(&mut ts) {
-                    Ok(data) => {
-                        let fb: FileBlock = (&data).into();
-                        if fb.is_valid() {
-                            if let Some(s) = map.lock().unwrap().get(&fb.file_id) {
-                                s.send(fb).unwrap();
-                                tcp_write(&mut ts, &TCPSignal::AC.into()).unwrap();
-                                // printlnl!("OK");
-                            } else {
-                                printlnl!("[Error] file id {} not avaliable", fb.file_id);
-                                thread::sleep(Duration::from_millis(200));
-                                tcp_write(&mut ts, &TCPSignal::Parden.into()).unwrap();
+                let frame = read_any_frame(&mut ts).and_then(|(got, data)| match got {
+                    FrameType::Block | FrameType::DeflatedBlock => Ok((got, data)),
+                    _ => Err(FrameError::Unexpected {
+                        expected: FrameType::Block,
+                        got,
+                    }),
+                });
+                match frame {
+                    Ok((got, data)) => {
+                        // the sender waits for the answer, so it is slowed down too
+                        download.take(data.len());
+                        let mut fb: FileBlock = (&data).into();
+                        if got == FrameType::DeflatedBlock && fb.is_valid() {
+                            let (file_id, index) = (fb.file_id, fb.index);
+                            match fb.inflated(FileBlocks::MAX_BLOCK_SIZE) {
+                                Ok(inflated) => fb = inflated,
+                                Err(e) => {
+                                    printlnl!("[Error] Block {file_id}:{index}: {e}");
+                                    tcp_write(&mut ts, &Vec::from(TCPSignal::Nack(file_id, index)))
+                                        .unwrap();
+                                    continue;
+                                }
                             }
+                        }
+                        let checked = checksum.load(Ordering::SeqCst);
+                        if fb.is_valid() && checked && !fb.is_intact() {
+                            printlnl!("[Error] Block {}:{} corrupt", fb.file_id, fb.index);
+                            tcp_write(&mut ts, &Vec::from(TCPSignal::Nack(fb.file_id, fb.index)))
+                                .unwrap();
+                        } else if fb.is_valid() {
+                            let (file_id, index) = (fb.file_id, fb.index);
+                            let file = map.lock().unwrap().get(&file_id).cloned();
+                            let taken = file.is_some_and(|(_, s, limit)| {
+                                limit.take(data.len());
+                                s.send(fb).is_ok()
+                            });
+                            if !taken {
+                                // the run ended, a late copy of a block is not sent again
+                                println!("[Drop] Block {file_id}:{index} of a file not received");
+                            }
+                            tcp_write(&mut ts, &Vec::from(TCPSignal::AC)).unwrap();
                         } else {
                             printlnl!("[Error] File block not valid: {:?}", fb);
                             thread::sleep(Duration::from_millis(200));
-                            tcp_write(&mut ts, &TCPSignal::Parden.into()).unwrap();
+                            tcp_write(&mut ts, &Vec::from(TCPSignal::Parden)).unwrap();
                             panic!();
                         }
                     }
@@ -261,36 +742,143 @@ impl MyBlockReceiver {
                 }
             }))
     }
-    /// return a run id
-    pub fn recv(&mut self, f: FileState, mut fb: FileBlocks) -> usize {
+    /// Receive within `limit` of the file, return a run id and the blocks still missing.
+    pub fn recv(
+        &mut self,
+        path: PathBuf,
+        mut fb: FileBlocks,
+        limit: MyRateLimit,
+    ) -> (usize, Vec<usize>) {
         // let mut slf = self.clone();
         // printlnl!("{:#?}", fb);
-        let (send, recv) = mpsc::channel();
-        self.allocate_map.lock().unwrap().insert(fb.id, send);
         let id = self.next_id();
+        if let Err(e) = fb.create(&path) {
+            printlnl!("[Error] Cannot create file {:?}: {e}", path);
+            self.msg
+                .send(MyCommand::ReceiveFileError(
+                    id,
+                    ReceiveFileErrorType::CannotWriteFile,
+                ))
+                .unwrap();
+            return (id, vec![]);
+        }
+        let remaining = fb.remaining.iter().copied().collect();
+        // a former run of the same file stops when its sender is replaced
+        let (send, recv) = mpsc::channel();
+        self.allocate_map
+            .lock()
+            .unwrap()
+            .insert(fb.id, (id, send, limit));
+        let msg = self.msg.clone();
+        let checked = self.checksum.load(Ordering::SeqCst);
+        let map = Arc::clone(&self.allocate_map);
+        let file_id = fb.id;
         thread::spawn(move || {
+            Self::write_blocks(id, fb, recv, msg, checked);
+            // unless replaced by a later run
+            let mut map = map.lock().unwrap();
+            if map.get(&file_id).is_some_and(|(run, ..)| *run == id) {
+                map.remove(&file_id);
+            }
+        });
+        (id, remaining)
+    }
+
+    /// Write the blocks of run `id` as they come, until the file is complete
+    /// or its sender is dropped.
+    fn write_blocks(
+        id: usize,
+        mut fb: FileBlocks,
+        recv: Receiver<FileBlock>,
+        msg: Sender<MyCommand>,
+        checked: bool,
+    ) {
+        // received again if corrupt
+        let mut attempts = 1;
+        loop {
             while !fb.is_finished() {
                 match recv.recv() {
                     Ok(b) => {
                         println!("Receive block {:?} of file {:?}!", b.index, b.file_id);
-                        fb.set(b);
+                        if let Err(e) = fb.set(b) {
+                            printlnl!("[Error] Write block error: {e}");
+                            msg.send(MyCommand::ReceiveFileError(
+                                id,
+                                ReceiveFileErrorType::CannotWriteFile,
+                            ))
+                            .unwrap();
+                            return;
+                        }
+                        let done = fb.block_num - fb.remaining.len();
+                        if done.is_multiple_of(FileBlocks::PERSIST_INTERVAL) {
+                            if let Err(e) = fb.persist() {
+                                printlnl!("[Error] Save resume state error: {e}");
+                            }
+                        }
+                        msg.send(MyCommand::ReceiveFileOk(
+                            id,
+                            ReceiveFileOkType::ReceiveProgress(done as f32 / fb.block_num as f32),
+                        ))
+                        .unwrap();
                     }
-                    Err(e) => {
-                        printlnl!("[Error] {e}");
-                        panic!();
+                    Err(_) => {
+                        println!("Receive file {} interrupted, save for resuming", fb.id);
+                        if let Err(e) = fb.persist() {
+                            printlnl!("[Error] Save resume state error: {e}");
+                        }
+                        return;
                     }
                 }
             }
             println!("FB finish!");
-            // save to file
-            fb.save(&f);
-        });
-        id
+            match fb.verify().map(|ok| ok || !checked) {
+                Ok(true) => break,
+                Ok(false) => {
+                    printlnl!("[Error] File {} does not match its digest", fb.id);
+                    if let Err(e) = fb.reset() {
+                        printlnl!("[Error] Save resume state error: {e}");
+                    }
+                    if attempts < RECEIVE_ATTEMPTS {
+                        attempts += 1;
+                        let mut remaining: Vec<usize> = fb.remaining.iter().copied().collect();
+                        remaining.sort_unstable();
+                        msg.send(MyCommand::ReceiveAgain(id, remaining)).unwrap();
+                        continue;
+                    }
+                    msg.send(MyCommand::ReceiveFileError(
+                        id,
+                        ReceiveFileErrorType::ChecksumMismatch,
+                    ))
+                    .unwrap();
+                    return;
+                }
+                Err(e) => {
+                    printlnl!("[Error] Verify file error: {e}");
+                    msg.send(MyCommand::ReceiveFileError(
+                        id,
+                        ReceiveFileErrorType::CannotWriteFile,
+                    ))
+                    .unwrap();
+                    return;
+                }
+            }
+        }
+        // flush to file
+        if let Err(e) = fb.save() {
+            printlnl!("[Error] Save file error: {e}");
+            msg.send(MyCommand::ReceiveFileError(
+                id,
+                ReceiveFileErrorType::CannotWriteFile,
+            ))
+            .unwrap();
+            return;
+        }
+        msg.send(MyCommand::ReceiveFileOk(id, ReceiveFileOkType::ReceiveDone))
+            .unwrap();
     }
 
     fn next_id(&self) -> usize {
-        self.counter
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+        self.counter.fetch_add(1, Ordering::SeqCst)
     }
 }
 
@@ -301,111 +889,428 @@ impl Clone for MyBlockReceiver {
             msg: self.msg.clone(),
             counter: self.counter.clone(),
             allocate_map: Arc::clone(&self.allocate_map),
+            checksum: Arc::clone(&self.checksum),
+            download: self.download.clone(),
         }
     }
 }
 
-struct MyReceiverState {}
+struct MyReceiverState {
+    /// device id of the sender
+    peer: String,
+    /// id of the file on the sender
+    file_id: usize,
+    /// blocks missing when the run started, sent back when the sender asks
+    remaining: Vec<usize>,
+    /// of this file only, kept when resuming
+    limit: MyRateLimit,
+}
 impl MyReceiverState {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(peer: String, file_id: usize, remaining: Vec<usize>, limit: MyRateLimit) -> Self {
+        Self {
+            peer,
+            file_id,
+            remaining,
+            limit,
+        }
     }
 }
 
+/// The connection with one peer
+struct MySession {
+    peer: MyPeerInfo,
+    /// tells a replaced connection of the same peer apart
+    number: usize,
+    is_host: bool,
+    /// commands to the connect loop, which stops when this is dropped
+    connect_sender: Sender<MyConnectCommand>,
+    /// data streams of this peer
+    block_sender: MyBlockSender,
+    block_receiver: MyBlockReceiver,
+}
+
 pub struct CommandLoop {
-    handle: isize,
+    /// main window, `None` when running without window
+    window: Option<Box<dyn MyWindow>>,
+    /// where received files are saved
+    download_dir: PathBuf,
     cmd: Receiver<MyCommand>,
     cmd_s: Sender<MyCommand>,
     msg_sender: Sender<MyMessage>,
 
-    connect_loop: Option<JoinHandle<()>>,
-    connect_sender: Option<Sender<MyConnectCommand>>,
+    /// connected peers by device id
+    sessions: HashMap<String, MySession>,
+    /// run ids and session numbers
+    counter: Arc<AtomicUsize>,
+    clipboard: MyClipboard,
+    /// clipboard images saved to be sent as files, with the number of peers
+    /// still sending each, deleted when none is
+    clipboard_files: HashMap<PathBuf, usize>,
+    /// of all sent and received files
+    upload: MyRateLimit,
+    download: MyRateLimit,
+    /// (min, max) block size of sent files
+    block_sizes: Arc<Mutex<(usize, usize)>>,
 
-    block_sender: MyBlockSender,
     block_sender_state: HashMap<usize, MySenderState>,
-
-    block_receiver: MyBlockReceiver,
     block_receiver_state: HashMap<usize, MyReceiverState>,
+
+    /// files posted by peers, by offer id
+    offers: HashMap<usize, MyPendingOffer>,
+    /// peers whose files are accepted without asking
+    accept_all: HashSet<String>,
+    /// accept every file without asking, when running without window
+    auto_accept: bool,
 }
 
 impl CommandLoop {
     pub fn new(
-        handle: isize,
+        window: Option<Box<dyn MyWindow>>,
         sm: Sender<MyMessage>,
         sc: Sender<MyCommand>,
         rc: Receiver<MyCommand>,
     ) -> Self {
         Self {
-            block_sender: MyBlockSender::new(sc.clone()),
             block_sender_state: HashMap::new(),
-            block_receiver: MyBlockReceiver::new(sc.clone()),
             block_receiver_state: HashMap::new(),
 
-            handle: handle,
+            window,
+            download_dir: FileState::DOWNLOAD_DIR.into(),
             cmd: rc,
             cmd_s: sc,
             msg_sender: sm,
 
-            connect_loop: None,
-            connect_sender: None,
+            sessions: HashMap::new(),
+            counter: Arc::new(AtomicUsize::new(1)),
+            clipboard: MyClipboard::new(),
+            clipboard_files: HashMap::new(),
+            upload: MyRateLimit::new(0),
+            download: MyRateLimit::new(0),
+            block_sizes: Arc::new(Mutex::new(FileBlocks::DEFAULT_BLOCK_SIZES)),
+
+            offers: HashMap::new(),
+            accept_all: HashSet::new(),
+            auto_accept: false,
         }
     }
 
+    pub fn with_download_dir(mut self, download_dir: PathBuf) -> Self {
+        self.download_dir = download_dir;
+        self
+    }
+
+    /// bytes per second of all sent and received files, 0 for no limit
+    pub fn with_limits(self, upload: u64, download: u64) -> Self {
+        self.upload.set_rate(upload);
+        self.download.set_rate(download);
+        self
+    }
+
+    /// bounds of the block size chosen for each sent file, in bytes
+    pub fn with_block_sizes(self, min: usize, max: usize) -> Self {
+        *self.block_sizes.lock().unwrap() = FileBlocks::block_size_bounds(min, max);
+        self
+    }
+
+    pub fn with_auto_accept(mut self, auto_accept: bool) -> Self {
+        self.auto_accept = auto_accept;
+        self
+    }
+
     pub fn run(mut self) -> JoinHandle<()> {
+        self.clipboard.watch(self.cmd_s.clone());
         thread::spawn(move || {
             while let Ok(cmd) = self.cmd.recv() {
                 match cmd {
                     MyCommand::TrayShow => self.to_show(),
                     MyCommand::TrayHide => self.to_hide(),
-                    MyCommand::AcceptListener(tls, ts) => {
+                    MyCommand::AcceptListener(tls, ts, peer) => {
                         // println!("MyCommand::AcceptListener");
-                        self.run_connect_loop(ts, Some(tls));
+                        let id = peer.id.clone();
+                        self.run_connect_loop(ts, Some(tls), peer);
+                        self.resume_sending(&id);
                     }
-                    MyCommand::AcceptConnector(ts) => {
+                    MyCommand::AcceptConnector(ts, peer) => {
                         // println!("MyCommand::AcceptConnector");
-                        self.run_connect_loop(ts, None);
+                        let id = peer.id.clone();
+                        self.run_connect_loop(ts, None, peer);
+                        self.resume_sending(&id);
                     }
-                    MyCommand::AddTcpSender(ts) => self.block_sender.push(ts),
-                    MyCommand::AddTcpReceiver(ts) => self.block_receiver.push(ts),
-                    MyCommand::SendFiles(files) => {
-                        // todo: Move add tcp stream inside run not here
-                        self.connect_sender
-                            .as_ref()
-                            .unwrap()
-                            .send(MyConnectCommand::AddTcpStream)
-                            .unwrap();
-                        for mut f in files {
-                            let id = self.block_sender.send(f.clone());
-                            if id.id != 0 {
-                                f.f.is_local = false;
-                                self.block_sender_state.insert(id.id, MySenderState::new());
-                                self.connect_sender
-                                    .as_ref()
-                                    .unwrap()
-                                    .send(TCPSignal::PostFile(f.f, id).into())
-                                    .unwrap();
-                            } else {
-                                // error send file
-                                printlnl!("error send file");
+                    MyCommand::AddTcpSender(peer, ts) => match self.sessions.get_mut(&peer) {
+                        Some(session) => session.block_sender.push(ts),
+                        None => {
+                            printlnl!("[Error] Data stream of unknown peer {peer}");
+                        }
+                    },
+                    MyCommand::AddTcpReceiver(peer, ts) => match self.sessions.get_mut(&peer) {
+                        Some(session) => session.block_receiver.push(ts),
+                        None => {
+                            printlnl!("[Error] Data stream of unknown peer {peer}");
+                        }
+                    },
+                    MyCommand::SendFiles(peer, files) => self.send_files(&peer, files),
+                    MyCommand::FileLoaded(peer, file, fb) => self.post_loaded(&peer, file, fb),
+                    MyCommand::SendRemaining(peer, id, remaining) => {
+                        let state = self
+                            .block_sender_state
+                            .get_mut(&id)
+                            .filter(|s| s.peer == peer);
+                        match (state, self.sessions.get_mut(&peer)) {
+                            (Some(state), Some(session)) => {
+                                state.stop();
+                                let mut fb = state.blocks.clone();
+                                fb.remaining = remaining
+                                    .into_iter()
+                                    .filter(|i| *i < fb.block_num)
+                                    .collect();
+                                println!("Send file {id} with {} blocks left", fb.remaining.len());
+                                let limit = state.limit.clone();
+                                state.sent = None;
+                                state.stop = Some(session.block_sender.send(fb, limit));
+                            }
+                            _ => {
+                                printlnl!("[Error] No file {id} to send to {peer}");
                             }
                         }
                     }
+                    MyCommand::SendFileOk(id, SendFileOkType::SendDone(stats)) => {
+                        println!("Send file {id}: every block sent, {stats}");
+                        if let Some(state) = self.block_sender_state.get_mut(&id) {
+                            state.sent = Some(stats);
+                        }
+                        self.finish_sending(id);
+                    }
                     MyCommand::SendFileOk(id, tp) => {
                         println!("Send file {id} ok with {:?}", tp);
-                        if tp.is_ok() {
-                            self.block_sender_state.remove(&id);
+                        self.msg_sender
+                            .send(MyMessage::SendFile(id, Ok(tp)))
+                            .unwrap();
+                    }
+                    MyCommand::FileReceived(peer, id, intact) => {
+                        println!("File {id} received by {peer}, intact: {intact}");
+                        let state = self
+                            .block_sender_state
+                            .get_mut(&id)
+                            .filter(|s| s.peer == peer);
+                        if let Some(state) = state {
+                            state.intact = Some(intact);
                         }
+                        self.finish_sending(id);
                     }
                     MyCommand::SendFileError(id, tp) => {
                         println!("Send file {id} error with {:?}", tp);
+                        self.msg_sender
+                            .send(MyMessage::SendFile(id, Err(tp)))
+                            .unwrap();
                     }
-                    MyCommand::ReceiveFile(f, mut fb) => {
-                        fb.init();
-                        if self.block_receiver_state.contains_key(&fb.id) {
-                            printlnl!("[Error] Cannot have two runs with same id!");
+                    MyCommand::ReceiveFile(peer, f, fb) => {
+                        let Some(session) = self.sessions.get(&peer) else {
+                            printlnl!("[Error] File posted by unknown peer {peer}");
+                            continue;
+                        };
+                        // a file posted again replaces its offer
+                        let file_id = fb.id;
+                        self.offers
+                            .retain(|_, o| o.offer.peer != peer || o.blocks.id != file_id);
+                        // and is resumed without asking again
+                        let resumed = self
+                            .block_receiver_state
+                            .values()
+                            .any(|s| s.peer == peer && s.file_id == file_id);
+                        if self.auto_accept || resumed || self.accept_all.contains(&peer) {
+                            self.receive_file(&peer, f, fb);
                         } else {
-                            let id = self.block_receiver.recv(f, fb);
-                            self.block_receiver_state.insert(id, MyReceiverState::new());
+                            let id = self.counter.fetch_add(1, Ordering::SeqCst);
+                            let offer = MyOffer {
+                                id,
+                                peer: peer.clone(),
+                                peer_name: session.peer.name.clone(),
+                                name: f.name.clone(),
+                                size: fb.file_size,
+                                is_folder: f.is_folder,
+                            };
+                            let pending = MyPendingOffer {
+                                offer,
+                                file: f,
+                                blocks: fb,
+                                queried: false,
+                            };
+                            self.offers.insert(id, pending);
+                        }
+                        self.send_offers();
+                    }
+                    MyCommand::QueryRemaining(peer, file_id) => {
+                        let offer = self
+                            .offers
+                            .values_mut()
+                            .find(|o| o.offer.peer == peer && o.blocks.id == file_id);
+                        match offer {
+                            Some(offer) => offer.queried = true,
+                            None => self.answer_remaining(&peer, file_id),
+                        }
+                    }
+                    MyCommand::AnswerOffer(id, answer) => {
+                        let Some(pending) = self.offers.remove(&id) else {
+                            continue;
+                        };
+                        let peer = pending.offer.peer.clone();
+                        match answer {
+                            OfferAnswer::Accept => self.accept_offer(pending),
+                            OfferAnswer::AcceptAll => {
+                                self.accept_all.insert(peer.clone());
+                                let ids: Vec<usize> = self
+                                    .offers
+                                    .iter()
+                                    .filter(|(_, o)| o.offer.peer == peer)
+                                    .map(|(id, _)| *id)
+                                    .collect();
+                                self.accept_offer(pending);
+                                for id in ids {
+                                    if let Some(pending) = self.offers.remove(&id) {
+                                        self.accept_offer(pending);
+                                    }
+                                }
+                            }
+                            OfferAnswer::Reject => {
+                                if let Some(session) = self.sessions.get(&peer) {
+                                    session
+                                        .connect_sender
+                                        .send(TCPSignal::Reject(pending.blocks.id).into())
+                                        .unwrap();
+                                }
+                                self.msg_sender
+                                    .send(format!("Rejected {}", pending.offer.name).into())
+                                    .unwrap();
+                            }
+                        }
+                        self.send_offers();
+                    }
+                    MyCommand::FileRejected(peer, file_id) => {
+                        let rejected = self
+                            .block_sender_state
+                            .get(&file_id)
+                            .is_some_and(|s| s.peer == peer);
+                        if !rejected {
+                            continue;
+                        }
+                        if let Some(mut state) = self.block_sender_state.remove(&file_id) {
+                            state.stop();
+                            self.drop_clipboard_file(&state.file);
+                            let name = self
+                                .sessions
+                                .get(&peer)
+                                .map_or(peer, |s| s.peer.name.clone());
+                            self.msg_sender
+                                .send(format!("{name} rejected {}", state.file.name).into())
+                                .unwrap();
+                            self.msg_sender
+                                .send(MyMessage::SendFile(
+                                    file_id,
+                                    Err(SendFileErrorType::Rejected),
+                                ))
+                                .unwrap();
+                        }
+                    }
+                    MyCommand::ReceiveFileOk(id, tp) => {
+                        println!("Receive file {id} ok with {:?}", tp);
+                        if tp.is_ok() {
+                            // the sender waits for this before the file counts as sent
+                            if let Some(state) = self.block_receiver_state.remove(&id) {
+                                self.tell_received(&state, true);
+                            }
+                        }
+                        self.msg_sender
+                            .send(MyMessage::ReceiveFile(id, Ok(tp)))
+                            .unwrap();
+                    }
+                    MyCommand::ReceiveFileError(id, tp) => {
+                        println!("Receive file {id} error with {:?}", tp);
+                        // blocks still coming are dropped, the sender stops
+                        match (self.block_receiver_state.remove(&id), &tp) {
+                            (Some(state), ReceiveFileErrorType::ChecksumMismatch) => {
+                                self.tell_received(&state, false);
+                            }
+                            (Some(state), _) => {
+                                if let Some(session) = self.sessions.get(&state.peer) {
+                                    let reject = TCPSignal::Reject(state.file_id);
+                                    let _ = session.connect_sender.send(reject.into());
+                                }
+                            }
+                            (None, _) => (),
+                        }
+                        self.msg_sender
+                            .send(MyMessage::ReceiveFile(id, Err(tp)))
+                            .unwrap();
+                    }
+                    MyCommand::ReceiveAgain(id, remaining) => {
+                        let Some(state) = self.block_receiver_state.get_mut(&id) else {
+                            continue;
+                        };
+                        printlnl!("[Error] File {id} does not match its digest, receive again");
+                        state.remaining = remaining.clone();
+                        if let Some(session) = self.sessions.get(&state.peer) {
+                            let again = TCPSignal::Remaining(state.file_id, remaining);
+                            let _ = session.connect_sender.send(again.into());
+                        }
+                    }
+                    MyCommand::SendClipboard(text) => self.send_clipboard(text),
+                    MyCommand::ReceiveClipboard(peer, clip) => {
+                        let name = self
+                            .sessions
+                            .get(&peer)
+                            .map_or(peer, |s| s.peer.name.clone());
+                        match self.clipboard.set(clip) {
+                            Ok(true) => self
+                                .msg_sender
+                                .send(format!("Clipboard received from {name}").into())
+                                .unwrap(),
+                            Ok(false) => (),
+                            Err(e) => {
+                                printlnl!("[Clipboard][Error] {e}");
+                                self.msg_sender
+                                    .send(format!("Cannot set the clipboard: {e}").into())
+                                    .unwrap();
+                            }
+                        }
+                    }
+                    MyCommand::AutoClipboard(auto) => self.clipboard.set_auto(auto),
+                    MyCommand::SetLimit(limit, rate) => {
+                        println!("[Limit] {:?} to {rate} B/s", limit);
+                        let limit = match limit {
+                            MyLimit::Upload => Some(&self.upload),
+                            MyLimit::Download => Some(&self.download),
+                            MyLimit::Send(id) => self.block_sender_state.get(&id).map(|s| &s.limit),
+                            MyLimit::Receive(id) => {
+                                self.block_receiver_state.get(&id).map(|s| &s.limit)
+                            }
+                        };
+                        if let Some(limit) = limit {
+                            limit.set_rate(rate);
+                        }
+                    }
+                    MyCommand::SetBlockSizes(min, max) => {
+                        let (min, max) = FileBlocks::block_size_bounds(min, max);
+                        println!("[Block size] {min} to {max} B");
+                        *self.block_sizes.lock().unwrap() = (min, max);
+                    }
+                    MyCommand::SetDownloadDir(dir) => {
+                        println!("[Download dir] {:?}", dir);
+                        self.download_dir = dir;
+                    }
+                    MyCommand::ConnectLoopStop(peer, number) => {
+                        println!("[Connect Loop] Stopped");
+                        // a replaced connection of the peer may stop late
+                        if self.sessions.get(&peer).is_none_or(|s| s.number != number) {
+                            continue;
+                        }
+                        if let Some(session) = self.sessions.remove(&peer) {
+                            self.msg_sender
+                                .send(MyMessage::ConnectInterrupt(session.is_host))
+                                .unwrap();
+                            self.send_sessions();
+                            // its files are posted again if it comes back
+                            self.offers.retain(|_, o| o.offer.peer != peer);
+                            self.send_offers();
                         }
                     }
                     e => println!("[Unknown Command]{:#?}", e),
@@ -414,47 +1319,503 @@ impl CommandLoop {
         })
     }
 
-    fn run_connect_loop(&mut self, mut ts: TcpStream, host: Option<TcpListener>) {
+    fn run_connect_loop(&mut self, mut ts: MyStream, host: Option<MyStreams>, peer: MyPeerInfo) {
         let (sc, sx) = mpsc::channel();
-        self.connect_sender = Some(sc);
         let cmd_s = self.cmd_s.clone();
 
-        if let Err(e) = ts.set_read_timeout(Some(Duration::from_millis(2000))) {
+        if let Err(e) = ts
+            .sock()
+            .set_read_timeout(Some(Duration::from_millis(2000)))
+        {
             println!("[Connect Loop fail to][Set read timeout]: {e}");
         }
-        if let Err(e) = ts.set_write_timeout(Some(Duration::from_millis(2000))) {
+        if let Err(e) = ts
+            .sock()
+            .set_write_timeout(Some(Duration::from_millis(2000)))
+        {
             println!("[Connect Loop fail to][Set write timeout]: {e}");
         }
         println!("[Ready for connect loop]");
+        let is_host = host.is_some();
         if host.is_none() {
-            if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+            if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                 println!("[Signal][Send] Error {e}");
             }
         }
         println!("[Enter connect loop]");
-        self.connect_loop = Some(thread::spawn(move || connect_loop(ts, cmd_s, sx, host)));
+        let number = self.counter.fetch_add(1, Ordering::SeqCst);
+        let id = peer.id.clone();
+        thread::spawn(move || connect_loop(ts, cmd_s, sx, host, (id, number)));
+        let session = MySession {
+            number,
+            is_host,
+            block_sender: MyBlockSender::new(
+                self.cmd_s.clone(),
+                sc.clone(),
+                self.counter.clone(),
+                self.upload.clone(),
+                self.block_sizes.clone(),
+            ),
+            block_receiver: MyBlockReceiver::new(
+                self.cmd_s.clone(),
+                self.counter.clone(),
+                self.download.clone(),
+            ),
+            connect_sender: sc,
+            peer,
+        };
+        session
+            .block_receiver
+            .checksum
+            .store(session.peer.has(CAP_CHECKSUM), Ordering::SeqCst);
+        session
+            .block_sender
+            .deflate
+            .store(session.peer.has(CAP_DEFLATE), Ordering::SeqCst);
+        println!(
+            "[Peer] {} with {:?}",
+            session.peer.name, session.peer.capabilities
+        );
+        // the same device connected again, its former connection is broken
+        if let Some(old) = self.sessions.insert(session.peer.id.clone(), session) {
+            println!("[Connect Loop] Replace the connection of {}", old.peer.name);
+            let _ = old.connect_sender.send(MyConnectCommand::ToStop);
+        }
+        self.send_sessions();
     }
 
-    fn to_hide(&mut self) {
-        unsafe {
-            windows_sys::Win32::UI::WindowsAndMessaging::ShowWindow(
-                self.handle,
-                windows_sys::Win32::UI::WindowsAndMessaging::SW_HIDE,
-            );
+    /// Tell the window which peers are connected.
+    fn send_sessions(&self) {
+        let mut peers: Vec<MyPeerInfo> = self.sessions.values().map(|s| s.peer.clone()).collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        self.msg_sender.send(MyMessage::Sessions(peers)).unwrap();
+    }
+
+    /// Tell the window which files wait to be accepted.
+    fn send_offers(&self) {
+        let mut offers: Vec<MyOffer> = self.offers.values().map(|o| o.offer.clone()).collect();
+        offers.sort_by_key(|o| o.id);
+        self.msg_sender.send(MyMessage::Offers(offers)).unwrap();
+    }
+
+    /// Start writing the file posted by the peer.
+    fn receive_file(&mut self, peer: &str, f: FileState, fb: FileBlocks) {
+        let Some(session) = self.sessions.get_mut(peer) else {
+            return;
+        };
+        // the file is posted again when resuming, replace the former run
+        let file_id = fb.id;
+        let former = self
+            .block_receiver_state
+            .iter()
+            .find(|(_, s)| s.peer == peer && s.file_id == file_id)
+            .map(|(id, s)| (*id, s.limit.clone()));
+        let limit = match former {
+            Some((id, limit)) => {
+                self.block_receiver_state.remove(&id);
+                limit
+            }
+            None => MyRateLimit::new(0),
+        };
+        // a different file of the same name is not overwritten
+        let path = fb.free_path(&f.get_path_in(&self.download_dir));
+        self.msg_sender
+            .send(format!("Receiving {:?} from {}", path, session.peer.name).into())
+            .unwrap();
+        let (id, remaining) = session.block_receiver.recv(path, fb, limit.clone());
+        self.block_receiver_state.insert(
+            id,
+            MyReceiverState::new(peer.to_string(), file_id, remaining, limit),
+        );
+    }
+
+    /// Receive the offered file, and answer the sender if it waits.
+    fn accept_offer(&mut self, pending: MyPendingOffer) {
+        let peer = pending.offer.peer;
+        let file_id = pending.blocks.id;
+        self.receive_file(&peer, pending.file, pending.blocks);
+        if pending.queried {
+            self.answer_remaining(&peer, file_id);
+        }
+    }
+
+    /// Tell the sender which blocks of the file are missing, so that it sends them.
+    fn answer_remaining(&self, peer: &str, file_id: usize) {
+        let remaining = self
+            .block_receiver_state
+            .values()
+            .find(|s| s.peer == peer && s.file_id == file_id)
+            .map(|s| s.remaining.clone());
+        match (remaining, self.sessions.get(peer)) {
+            (Some(remaining), Some(session)) => session
+                .connect_sender
+                .send(TCPSignal::Remaining(file_id, remaining).into())
+                .unwrap(),
+            _ => {
+                printlnl!("[Error] Query remaining of unknown file {file_id}");
+            }
+        }
+    }
+
+    /// Post the file and ask the receiver which blocks are still missing,
+    /// the blocks are sent when it answers with `TCPSignal::Remaining`.
+    fn post_file(session: &MySession, state: &MySenderState) {
+        let connect_sender = &session.connect_sender;
+        connect_sender
+            .send(TCPSignal::PostFile(state.file.clone(), state.blocks.info()).into())
+            .unwrap();
+        connect_sender
+            .send(TCPSignal::QueryRemaining(state.blocks.id).into())
+            .unwrap();
+    }
+
+    /// Post again the unfinished files of the peer after reconnecting.
+    fn resume_sending(&mut self, peer: &str) {
+        let Some(session) = self.sessions.get(peer) else {
+            return;
+        };
+        let mut states: Vec<_> = self
+            .block_sender_state
+            .values_mut()
+            .filter(|s| s.peer == peer)
+            .collect();
+        if states.is_empty() {
+            return;
+        }
+        // one stream first, the others join while the files are sent,
+        // as adding a stream takes a while
+        session.block_sender.open_streams(1);
+        for state in states.iter_mut() {
+            state.stop();
+        }
+        for state in states {
+            println!("Resume sending file {}", state.blocks.id);
+            Self::post_file(session, state);
+        }
+        session.block_sender.open_streams(DATA_STREAMS);
+    }
+
+    /// Load the files on a worker thread, as reading a big file for its digest takes
+    /// a while, they are posted to the peer by `post_loaded`.
+    fn send_files(&mut self, peer: &str, files: Vec<FileStateExtend>) {
+        let Some(session) = self.sessions.get_mut(peer) else {
+            printlnl!("[Error] Send files to unknown peer {peer}");
+            self.msg_sender
+                .send("Cannot send: the peer is not connected".to_string().into())
+                .unwrap();
+            return;
+        };
+        session.block_sender.open_streams(1);
+        let mut block_sender = session.block_sender.clone();
+        let cmd_s = self.cmd_s.clone();
+        let peer = peer.to_string();
+        thread::spawn(move || {
+            for f in files {
+                let fb = block_sender.load(&f.f).map_err(|e| e.to_string());
+                let _ = cmd_s.send(MyCommand::FileLoaded(peer.clone(), f.f, fb));
+            }
+        });
+    }
+
+    /// Post a file loaded by `send_files`, or after reconnecting if the peer left meanwhile.
+    fn post_loaded(&mut self, peer: &str, mut file: FileState, fb: Result<FileBlocks, String>) {
+        let fb = match fb {
+            Ok(fb) => fb,
+            Err(e) => {
+                printlnl!("error send file: {e}");
+                self.drop_clipboard_file(&file);
+                self.msg_sender
+                    .send(MyMessage::CannotSend(file.name, e))
+                    .unwrap();
+                return;
+            }
+        };
+        file.is_local = false;
+        let state = MySenderState::new(peer.to_string(), file, fb);
+        if let Some(session) = self.sessions.get(peer) {
+            Self::post_file(session, &state);
+            session.block_sender.open_streams(DATA_STREAMS);
+        }
+        self.block_sender_state.insert(state.blocks.id, state);
+    }
+
+    /// A file counts as sent once every block is acknowledged and the receiver
+    /// found the whole file intact, whichever is known last.
+    fn finish_sending(&mut self, id: usize) {
+        let res = match self.block_sender_state.get(&id) {
+            Some(MySenderState {
+                sent: Some(stats),
+                intact: Some(true),
+                ..
+            }) => Ok(SendFileOkType::SendDone(*stats)),
+            Some(MySenderState {
+                intact: Some(false),
+                ..
+            }) => Err(SendFileErrorType::ChecksumMismatch),
+            _ => return,
+        };
+        if let Some(mut state) = self.block_sender_state.remove(&id) {
+            state.stop();
+            self.drop_clipboard_file(&state.file);
+        }
+        self.msg_sender.send(MyMessage::SendFile(id, res)).unwrap();
+    }
+
+    /// A send of `file` ended, delete it if it is a clipboard image no other peer is sending.
+    fn drop_clipboard_file(&mut self, file: &FileState) {
+        let Some(path) = &file.is_linked else {
+            return;
+        };
+        let Some(left) = self.clipboard_files.get_mut(path) else {
+            return;
+        };
+        *left -= 1;
+        if *left == 0 {
+            self.clipboard_files.remove(path);
+            if let Err(e) = fs::remove_file(path) {
+                printlnl!("[Clipboard][Error] Cannot delete {:?}: {e}", path);
+            }
+        }
+    }
+
+    /// Tell the sender whether the file of the run is intact.
+    fn tell_received(&self, state: &MyReceiverState, intact: bool) {
+        if let Some(session) = self.sessions.get(&state.peer) {
+            let received = TCPSignal::Received(state.file_id, intact);
+            let _ = session.connect_sender.send(received.into());
+        }
+    }
+
+    /// Send the clip to every peer taking it.
+    /// `None` reads the clipboard, when asked by the user.
+    fn send_clipboard(&mut self, clip: Option<MyClip>) {
+        let asked = clip.is_none();
+        let clip = match clip.map_or_else(|| self.clipboard.content(), Ok) {
+            Ok(clip) => clip,
+            Err(e) => {
+                printlnl!("[Clipboard][Error] {e}");
+                self.msg_sender
+                    .send(format!("Cannot read the clipboard: {e}").into())
+                    .unwrap();
+                return;
+            }
+        };
+        let cap = match &clip {
+            MyClip::Text(_) => CAP_CLIPBOARD,
+            MyClip::Image(_) => CAP_CLIPBOARD_IMAGE,
+        };
+        let peers: Vec<String> = self
+            .sessions
+            .values()
+            .filter(|s| s.peer.has(cap))
+            .map(|s| s.peer.id.clone())
+            .collect();
+        // copying while nobody is connected is no news in automatic mode
+        if !asked && peers.is_empty() {
+            return;
+        }
+        match clip {
+            MyClip::Text(text) if text.len() > MAX_CLIPBOARD_TEXT => {
+                self.msg_sender
+                    .send("The clipboard text is too large to send".to_string().into())
+                    .unwrap();
+                return;
+            }
+            MyClip::Image(png) if png.len() > MAX_CLIPBOARD_IMAGE => {
+                // too large for a frame, sent as a file
+                let file = match clipboard::save_image(&png).and_then(|p| FileState::from_path(&p))
+                {
+                    Ok(file) => file,
+                    Err(e) => {
+                        printlnl!("[Clipboard][Error] Cannot save image: {e}");
+                        self.msg_sender
+                            .send(format!("Cannot save the clipboard image: {e}").into())
+                            .unwrap();
+                        return;
+                    }
+                };
+                if let Some(path) = &file.is_linked {
+                    self.clipboard_files.insert(path.clone(), peers.len());
+                }
+                for peer in peers.iter() {
+                    let files = vec![FileStateExtend {
+                        f: file.clone(),
+                        is_selected: true,
+                    }];
+                    self.send_files(peer, files);
+                }
+                self.msg_sender
+                    .send(
+                        format!(
+                            "Clipboard image sent as {} to {} device(s)",
+                            file.name,
+                            peers.len()
+                        )
+                        .into(),
+                    )
+                    .unwrap();
+                return;
+            }
+            clip => {
+                let signal = match clip {
+                    MyClip::Text(text) => TCPSignal::Clipboard(text),
+                    MyClip::Image(png) => TCPSignal::ClipboardImage(png),
+                };
+                for peer in peers.iter() {
+                    self.sessions[peer]
+                        .connect_sender
+                        .send(signal.clone().into())
+                        .unwrap();
+                }
+            }
         }
+        self.msg_sender
+            .send(format!("Clipboard sent to {} device(s)", peers.len()).into())
+            .unwrap();
+    }
+
+    fn to_hide(&mut self) {
+        let Some(window) = &self.window else {
+            return;
+        };
+        window.hide();
         self.msg_sender
             .send(format!("[COMMAND] Window hide").into())
             .unwrap();
     }
     fn to_show(&mut self) {
-        unsafe {
-            windows_sys::Win32::UI::WindowsAndMessaging::ShowWindow(
-                self.handle,
-                windows_sys::Win32::UI::WindowsAndMessaging::SW_SHOW,
-            );
-        }
+        let Some(window) = &self.window else {
+            return;
+        };
+        window.show();
         self.msg_sender
             .send(format!("[COMMAND] Window show").into())
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::command::*;
+
+    fn queue(blocks: usize) -> SendQueue {
+        SendQueue {
+            todo: (0..blocks).collect(),
+            workers: 1,
+            ..Default::default()
+        }
+    }
+
+    fn sent(pos: usize) -> SentBlock {
+        SentBlock {
+            pos,
+            bytes: 100,
+            wire: 40,
+            at: Instant::now(),
+        }
+    }
+
+    /// take blocks until the window of a stream is full
+    fn fill(q: &mut SendQueue, window: &mut VecDeque<SentBlock>) {
+        while window.len() < SEND_WINDOW {
+            let Some(pos) = q.take() else {
+                break;
+            };
+            window.push_back(sent(pos));
+        }
+    }
+
+    #[test]
+    fn test_send_window() {
+        let mut q = queue(20);
+        let mut window = VecDeque::new();
+        fill(&mut q, &mut window);
+        assert_eq!(window.len(), SEND_WINDOW);
+        assert_eq!(q.in_flight, SEND_WINDOW);
+
+        // every answer makes room for the next block
+        let first = window.pop_front().unwrap();
+        assert_eq!(q.done(&first), 1);
+        fill(&mut q, &mut window);
+        assert_eq!(q.in_flight, SEND_WINDOW);
+        assert_eq!(window.back().unwrap().pos, SEND_WINDOW);
+
+        // a block received corrupt is sent again before the others
+        let corrupt = window.pop_front().unwrap();
+        q.retry(corrupt.pos);
+        fill(&mut q, &mut window);
+        assert_eq!(window.back().unwrap().pos, corrupt.pos);
+        assert_eq!(q.todo.front(), Some(&(SEND_WINDOW + 1)));
+    }
+
+    #[test]
+    fn test_send_broken_stream() {
+        let mut q = queue(20);
+        q.workers = 2;
+        let (mut a, mut b) = (VecDeque::new(), VecDeque::new());
+        fill(&mut q, &mut a);
+        fill(&mut q, &mut b);
+        assert_eq!(q.in_flight, 2 * SEND_WINDOW);
+        let lost: Vec<usize> = a.iter().map(|s| s.pos).collect();
+
+        // the blocks of the broken stream come first for the other one, in order
+        q.broken(a);
+        assert_eq!(q.workers, 1);
+        assert_eq!(q.in_flight, SEND_WINDOW);
+        while let Some(block) = b.pop_front() {
+            q.done(&block);
+        }
+        fill(&mut q, &mut b);
+        let resent: Vec<usize> = b.iter().map(|s| s.pos).collect();
+        assert_eq!(resent, lost);
+    }
+
+    #[test]
+    fn test_send_finished() {
+        let mut q = queue(3);
+        q.done = 7;
+        let mut window = VecDeque::new();
+        fill(&mut q, &mut window);
+        assert_eq!(window.len(), 3);
+        assert!(q.todo.is_empty());
+        // not before every block is answered
+        assert!(!q.finished());
+        while let Some(block) = window.pop_front() {
+            assert!(!q.finished());
+            q.done(&block);
+        }
+        assert!(q.finished());
+        assert_eq!(q.done, 10);
+        assert_eq!(q.stats.bytes, 300);
+        assert_eq!(q.stats.wire, 120);
+
+        // a failed file takes nothing more
+        let mut q = queue(3);
+        q.failed = true;
+        assert_eq!(q.take(), None);
+    }
+
+    #[test]
+    fn test_clipboard_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("clipboard.png");
+        std::fs::write(&path, b"png").unwrap();
+        let file = FileState::from_path(&path).unwrap();
+        let (sm, _rm) = mpsc::channel();
+        let (sc, rc) = mpsc::channel();
+        let mut cl = CommandLoop::new(None, sm, sc, rc);
+        cl.clipboard_files.insert(path.clone(), 2);
+
+        cl.drop_clipboard_file(&file);
+        assert!(path.exists());
+        // the last peer is done with it
+        cl.drop_clipboard_file(&file);
+        assert!(!path.exists());
+        assert!(cl.clipboard_files.is_empty());
+
+        // other files are left alone
+        std::fs::write(&path, b"png").unwrap();
+        cl.drop_clipboard_file(&file);
+        assert!(path.exists());
+    }
+}
@@ -1,38 +1,112 @@
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
-    io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::mpsc::{Receiver, Sender},
-    thread::{self, JoinHandle},
+    io::{ErrorKind, Read, Write},
+    net::{
+        IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, TcpStream, ToSocketAddrs,
+    },
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread,
     time::Duration,
 };
 
-use if_addrs::Ifv4Addr;
+use if_addrs::{IfAddr, Interface};
 
 use crate::{
+    clipboard::MyClip,
     command::{MyCommand, MyConnectCommand},
+    device::{self, Trust},
     file::FileState,
+    pake::{self, MySpake},
+    tls::{self, Fingerprint, MyStream},
 };
 
+/// Bumped on every incompatible change of `TCPSignal`, `FileBlock` or the framing,
+/// peers must have the same version.
+pub const PROTOCOL_VERSION: u32 = 6;
+/// blocks and whole files carry a sha256 to be checked
+pub const CAP_CHECKSUM: &str = "checksum";
+/// clipboard text is sent with `TCPSignal::Clipboard`
+pub const CAP_CLIPBOARD: &str = "clipboard";
+/// copied images are sent with `TCPSignal::ClipboardImage`
+pub const CAP_CLIPBOARD_IMAGE: &str = "clipboard-image";
+/// blocks which compress well are sent as `FrameType::DeflatedBlock`
+pub const CAP_DEFLATE: &str = "deflate";
+/// optional features of this build, used only if the peer has them too
+pub const CAPABILITIES: &[&str] = &[
+    CAP_CHECKSUM,
+    CAP_CLIPBOARD,
+    CAP_CLIPBOARD_IMAGE,
+    CAP_DEFLATE,
+];
+
+/// The first message on a control stream, before any `TCPSignal`.
+/// Its layout must stay the same in every version, so that any two builds can
+/// tell whether they understand each other.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Hello {
+    /// always `APP`, to tell other programs apart
+    app: String,
+    version: u32,
+    capabilities: Vec<String>,
+}
+const APP: &str = "file-net";
+
+/// A connection through the handshake. The data streams of a connection accepted
+/// by a listener come from the receiver, the connecting side opens its own.
+pub type MyConnection = (Option<MyStreams>, MyStream, MyPeerInfo);
+pub type Connected = Result<MyConnection, String>;
+
 pub struct MyTcplistener {
-    pub ip4: [(u8, String); 4],
+    /// (ip v4 or v6, `None` if the text is not a valid ip; text in the address box)
+    pub ip: (Option<IpAddr>, String),
+    /// scope id (interface index) of a link-local ipv6 address, 0 for none
+    pub scope_id: u32,
     pub port: (u16, String),
     pub state: ListenerState,
+    /// the address actually connected, one of those the host name resolves to
+    pub peer: Option<SocketAddr>,
+    /// certificate fingerprint of the connected peer
+    pub fingerprint: Option<Fingerprint>,
+    /// the connected device
+    pub device: Option<MyPeerInfo>,
+    /// pairing code, shown on the listening side and typed on the connecting side
+    pub code: String,
+    /// why the last connect failed
+    pub error: String,
     pub name: String,
-    pub handle: Option<JoinHandle<(Option<TcpListener>, Option<TcpStream>, Vec<u8>)>>,
+    /// devices connected through this listener
+    pub clients: Vec<MyPeerInfo>,
+    /// connections from the listen or connect thread
+    connected: Option<Receiver<Connected>>,
+    /// the connection `get_tls` hands out next
+    next: Option<Connected>,
+    /// stops the listen thread
+    stop: Option<Arc<AtomicBool>>,
+    /// wrong codes tried on the listen thread
+    pair_guard: Option<Arc<Mutex<PairGuard>>>,
 }
 impl MyTcplistener {
     pub const NULL: MyTcplistener = Self {
-        ip4: [
-            (0, String::new()),
-            (0, String::new()),
-            (0, String::new()),
-            (0, String::new()),
-        ],
+        ip: (None, String::new()),
+        scope_id: 0,
         port: (0, String::new()),
         state: ListenerState::READY,
+        peer: None,
+        fingerprint: None,
+        device: None,
+        code: String::new(),
+        error: String::new(),
         name: String::new(),
-        handle: None,
+        clients: Vec::new(),
+        connected: None,
+        next: None,
+        stop: None,
+        pair_guard: None,
     };
     pub fn with_name(mut self, name: String) -> Self {
         self.name = name;
@@ -40,89 +114,169 @@ impl MyTcplistener {
     }
 
     pub fn to_string(&self) -> String {
-        format!(
-            "{}.{}.{}.{}:{}",
-            self.ip4[0].0, self.ip4[1].0, self.ip4[2].0, self.ip4[3].0, self.port.0
-        )
+        match self.addr() {
+            Some(addr) => addr.to_string(),
+            None => format!("{}:{}", self.ip.1, self.port.0),
+        }
     }
 
-    pub fn get_tls(&mut self, host: bool) -> (Option<TcpListener>, Option<TcpStream>) {
-        let res = std::mem::replace(&mut self.handle, None);
-        match res.unwrap().join() {
-            Ok((tls, Some(mut stream), data)) if !data.is_empty() => {
-                let signal: TCPSignal = data.into();
-                match signal {
-                    TCPSignal::Accept { ip_addr, name } => {
-                        println!("[Tcp Connect Accept!] Connect to {name} with ip {ip_addr}");
-                        // self.info = format!("Connect to {name} with ip {ipAddr}");
-                    }
-                    e => {
-                        println!("[Tcp Connect Error!]: {:?}", e);
-                        self.state = ListenerState::FAIL;
-                        return (None, None);
-                    }
-                }
-                if host {
-                    let data: Vec<u8> = TCPSignal::Accept {
-                        ip_addr: self.to_string(),
-                        name: "host".to_string(),
-                    }
-                    .into();
-                    if let Err(e) = tcp_write(&mut stream, &data) {
-                        printlnl!("[Tcp Connect Accept Response Error]: {e}");
-                        self.state = ListenerState::FAIL;
-                        return (None, None);
-                    }
-                }
-                return (tls, Some(stream));
+    /// the socket address, `None` if no valid ip entered
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self.ip.0? {
+            IpAddr::V6(ip) => Some(SocketAddrV6::new(ip, self.port.0, 0, self.scope_id).into()),
+            ip => Some(SocketAddr::new(ip, self.port.0)),
+        }
+    }
+
+    /// ip is entered and can be listened on or connected to
+    fn has_ip(&self) -> bool {
+        self.ip.0.is_some_and(|ip| !ip.is_unspecified())
+    }
+
+    /// the text entered is a host name (like `build-box.local`) rather than an ip
+    pub fn host_name(&self) -> Option<&str> {
+        let host = self.ip.1.as_str();
+        let valid = |c: char| c.is_alphanumeric() || c == '-' || c == '.';
+        (self.ip.0.is_none() && !host.is_empty() && host.chars().all(valid)).then_some(host)
+    }
+
+    /// Whether only the port is missing after `text`: an ipv4, a bracketed ipv6
+    /// or a host name which cannot be the start of an ipv6 like `fe80`.
+    pub fn is_complete_host(text: &str) -> bool {
+        if let Some(v6) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            let ip = v6.split_once('%').map_or(v6, |(ip, _)| ip);
+            return ip.parse::<Ipv6Addr>().is_ok();
+        }
+        let valid = |c: char| c.is_alphanumeric() || c == '-' || c == '.';
+        text.parse::<Ipv4Addr>().is_ok()
+            || (text.chars().all(valid) && !text.chars().all(|c| c.is_ascii_hexdigit()))
+    }
+
+    /// Set the ip from text like `192.168.1.2`, `fe80::1%3` or `[fe80::1%eth0]`.
+    /// The scope may be an interface index or name.
+    pub fn set_ip(&mut self, text: &str) {
+        let text = text.trim();
+        self.ip.1 = text.to_string();
+        let text = text.trim_start_matches('[').trim_end_matches(']');
+        let (ip, scope) = match text.split_once('%') {
+            Some((ip, scope)) => (ip, Some(scope)),
+            None => (text, None),
+        };
+        self.ip.0 = ip.parse().ok();
+        self.scope_id = match (self.ip.0, scope) {
+            (Some(IpAddr::V6(_)), Some(scope)) => scope.parse().unwrap_or_else(|_| {
+                // interface name
+                if_addrs::get_if_addrs()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find(|i| i.name == scope)
+                    .and_then(|i| i.index)
+                    .unwrap_or_default()
+            }),
+            _ => 0,
+        };
+    }
+
+    /// Set ip and port from text like `192.168.1.2:8000` or `[fe80::1%3]:8000`.
+    /// Text without a port only sets the ip.
+    /// Return false if the text is neither an ip nor a host name.
+    pub fn set_addr(&mut self, text: &str) -> bool {
+        let text = text.trim();
+        let (ip, port) = if let Some((ip, port)) = text.rsplit_once("]:") {
+            (ip, Some(port))
+        } else {
+            match text.split_once(':') {
+                // exactly one ':', ipv4 with port
+                Some((ip, port)) if !port.contains(':') => (ip, Some(port)),
+                _ => (text, None),
+            }
+        };
+        self.set_ip(ip);
+        if let Some(port) = port {
+            self.port.0 = port.parse().unwrap_or_default();
+            self.port.1 = self.port.0.to_string();
+        }
+        self.ip.0.is_some() || self.host_name().is_some()
+    }
+
+    pub fn get_tls(&mut self) -> (Option<MyStreams>, Option<MyStream>) {
+        match self.next.take() {
+            Some(Ok((streams, stream, device))) => {
+                self.peer = stream.sock().peer_addr().ok();
+                self.fingerprint = stream.peer_fingerprint();
+                println!(
+                    "[Tcp Connect Accept!] Connect to {} ({}) with ip {:?}",
+                    device.name, device.id, self.peer
+                );
+                self.clients.retain(|c| c.id != device.id);
+                self.clients.push(device.clone());
+                self.device = Some(device);
+                (streams, Some(stream))
             }
-            Ok(e) => {
+            Some(Err(reason)) => {
                 self.state = ListenerState::FAIL;
-                println!("[TcpListener Receive Fail]: {:?}", e);
+                self.error = reason;
+                println!("[TcpListener Receive Fail]: {}", self.error);
                 (None, None)
             }
-            Err(e) => {
-                printlnl!("[TcpListener Receive Error]: {:?}", e);
+            None => {
+                printlnl!("[TcpListener Receive Error]: nothing connected");
                 self.state = ListenerState::FAIL;
                 (None, None)
             }
         }
     }
 
-    /// return true if the listener is connected to listen
+    /// Stop the listen thread, and forget the connection.
+    fn reset(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            stop.store(true, Ordering::SeqCst);
+        }
+        self.connected = None;
+        self.next = None;
+        self.pair_guard = None;
+        self.peer = None;
+        self.device = None;
+        self.clients.clear();
+        self.state = ListenerState::READY;
+        self.port.0 = 0;
+        self.port.1 = 0.to_string();
+    }
+
+    /// Too many wrong codes were tried, listen again with a new code to pair.
+    pub fn pairing_stopped(&self) -> bool {
+        self.pair_guard
+            .as_ref()
+            .is_some_and(|g| g.lock().unwrap().stopped())
+    }
+
+    /// return true if a new client is connected, to be taken by `get_tls`
     pub fn handle_listener(&mut self) -> bool {
         match self.state {
             ListenerState::TOLISTEN => {
-                if self.ip4[0].0 == 0
-                    && self.ip4[1].0 == 0
-                    && self.ip4[2].0 == 0
-                    && self.ip4[3].0 == 0
-                {
+                let Some(addr) = self.addr().filter(|_| self.has_ip()) else {
                     // not ready for lis
                     self.state = ListenerState::READY;
                     return false;
-                }
+                };
                 // start listening
-                let ip = self.to_string();
-                println!("Start listening to {ip}.");
-                match TcpListener::bind(ip) {
+                println!("Start listening to {addr}.");
+                match TcpListener::bind(addr) {
                     Ok(l) if l.local_addr().is_ok() => {
                         let add = l.local_addr().unwrap();
                         println!("[Listen Start] At {:?}.", add);
                         self.state = ListenerState::LISTENING;
                         self.port.0 = add.port();
                         self.port.1 = self.port.0.to_string();
-                        self.handle = Some(thread::spawn(move || match l.accept() {
-                            Ok(mut s) => {
-                                println!("[Accect From]: {:?}", s.1);
-                                let data = tcp_read(&mut s.0).unwrap_or_default();
-                                (Some(l), Some(s.0), data)
-                            }
-                            Err(e) => {
-                                printlnl!("[Accept Error]: {e}");
-                                (None, None, vec![])
-                            }
-                        }));
+                        let (sender, connected) = mpsc::channel();
+                        let stop = Arc::new(AtomicBool::new(false));
+                        self.connected = Some(connected);
+                        self.stop = Some(stop.clone());
+                        let guard = Arc::new(Mutex::new(PairGuard::default()));
+                        self.pair_guard = Some(guard.clone());
+                        let code = self.code.clone();
+                        let ip_addr = self.to_string();
+                        thread::spawn(move || listen(l, sender, stop, code, ip_addr, guard));
                     }
                     Ok(l) => {
                         printlnl!("[Listen Start][Error]:{:?}", l.local_addr().err().unwrap());
@@ -134,23 +288,25 @@ impl MyTcplistener {
                     }
                 }
             }
-            ListenerState::LISTENING => match &self.handle {
-                Some(h) if h.is_finished() => {
-                    self.state = ListenerState::ACCEPTED;
-                    return true;
-                }
-                None => {
+            // keeps listening after a client is connected
+            ListenerState::LISTENING => {
+                let Some(connected) = &self.connected else {
                     self.state = ListenerState::FAIL;
                     return false;
+                };
+                match connected.try_recv() {
+                    Ok(c) => {
+                        self.next = Some(c);
+                        return true;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => return false,
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        self.state = ListenerState::FAIL;
+                        return false;
+                    }
                 }
-                _ => return false,
-            },
-            ListenerState::TOSTOP => {
-                self.handle = None;
-                self.state = ListenerState::READY;
-                self.port.0 = 0;
-                self.port.1 = 0.to_string();
             }
+            ListenerState::TOSTOP => self.reset(),
             _ => (),
         }
         return false;
@@ -159,13 +315,11 @@ impl MyTcplistener {
     pub fn handle_connector(&mut self) -> bool {
         match self.state {
             ListenerState::TOLISTEN => {
-                if self.ip4[0].0 == 0
-                    && self.ip4[1].0 == 0
-                    && self.ip4[2].0 == 0
-                    && self.ip4[3].0 == 0
-                {
+                let addr = self.addr().filter(|_| self.has_ip());
+                let host = self.host_name().map(|h| h.to_string());
+                if addr.is_none() && host.is_none() {
                     // not ready for connect
-                    println!("[Cannot Connect] Please enter ip4");
+                    println!("[Cannot Connect] Please enter ip or host name");
                     self.state = ListenerState::READY;
                     return false;
                 }
@@ -175,110 +329,374 @@ impl MyTcplistener {
                     self.state = ListenerState::READY;
                     return false;
                 }
-                // start connect
-                let addr = self.into();
-                println!("Start connecting to {addr}.");
-                let Ok(mut stream) = TcpStream::connect_timeout(&addr, Duration::from_millis(2500))
-                else {
-                    println!("Couldn't connect to server...");
-                    self.state = ListenerState::FAIL;
-                    return false;
-                };
-                println!("Connected to {:?}.", stream.peer_addr());
+                // start connect, resolving the host name may take a while
+                let port = self.port.0;
+                let code = self.code.clone();
                 self.state = ListenerState::LISTENING;
-                let ip = self.to_string();
-                self.handle = Some(thread::spawn(move || {
-                    let data: Vec<u8> = TCPSignal::Accept {
-                        ip_addr: ip,
-                        name: "客户端".to_string(),
+                let (sender, connected) = mpsc::channel();
+                self.connected = Some(connected);
+                thread::spawn(move || {
+                    let _ = sender.send(connect(addr, host, port, &code));
+                });
+            }
+            ListenerState::LISTENING => {
+                let res = match self.connected.as_ref().map(|c| c.try_recv()) {
+                    Some(Ok(c)) => c,
+                    Some(Err(mpsc::TryRecvError::Empty)) => return false,
+                    _ => Err("Connect thread stopped".to_string()),
+                };
+                self.next = Some(res);
+                self.state = ListenerState::ACCEPTED;
+                return true;
+            }
+            ListenerState::TOSTOP => self.reset(),
+            _ => (),
+        }
+        return false;
+    }
+}
+
+/// Connect to the address, or to the host name, and go through the handshake.
+fn connect(addr: Option<SocketAddr>, host: Option<String>, port: u16, code: &str) -> Connected {
+    let addrs: Vec<SocketAddr> = match (addr, host) {
+        (Some(addr), _) => vec![addr],
+        (None, Some(host)) => match (host.as_str(), port).to_socket_addrs() {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                println!("[Cannot Connect] Cannot resolve {host}: {e}");
+                vec![]
+            }
+        },
+        (None, None) => vec![],
+    };
+    // try every address in turn
+    let Some(stream) = addrs.into_iter().find_map(|addr| {
+        println!("Start connecting to {addr}.");
+        TcpStream::connect_timeout(&addr, Duration::from_millis(2500))
+            .inspect_err(|e| println!("Couldn't connect to {addr}: {e}"))
+            .ok()
+    }) else {
+        println!("Couldn't connect to server...");
+        return Err("Couldn't connect to server".to_string());
+    };
+    let ip = stream.peer_addr().map_err(|e| e.to_string())?;
+    println!("Connected to {ip}.");
+    // the certificate is checked by the device trust in the handshake
+    let mut stream = MyStream::connect(stream, None).map_err(|e| {
+        printlnl!("[Connect TLS Error]: {e}");
+        e.to_string()
+    })?;
+    match handshake(&mut stream, None, code, &ip.to_string(), None) {
+        Ok(peer) => Ok((None, stream, peer)),
+        Err(e) => {
+            printlnl!("[Connect Handshake Error]: {e}");
+            Err(e.to_string())
+        }
+    }
+}
+
+/// The listen thread stops with the listener.
+impl Drop for MyTcplistener {
+    fn drop(&mut self) {
+        if let Some(stop) = &self.stop {
+            stop.store(true, Ordering::SeqCst);
+        }
+    }
+}
+
+type Routes = Arc<Mutex<HashMap<String, Route>>>;
+
+/// Where the data streams of a session accepted by a listener are sent.
+#[derive(Debug)]
+struct Route {
+    /// data streams must come from the same ip, with the same certificate
+    ip: IpAddr,
+    fingerprint: Option<Fingerprint>,
+    streams: Sender<MyStream>,
+    /// tells the route of this session from the one of a later session of the peer
+    number: usize,
+}
+
+/// Data streams of a session accepted by a listener,
+/// its route is removed when the session drops it.
+#[derive(Debug)]
+pub struct MyStreams {
+    streams: Receiver<MyStream>,
+    id: String,
+    number: usize,
+    routes: Routes,
+}
+
+impl MyStreams {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<MyStream, mpsc::RecvTimeoutError> {
+        self.streams.recv_timeout(timeout)
+    }
+}
+
+impl Drop for MyStreams {
+    fn drop(&mut self) {
+        let mut routes = self.routes.lock().unwrap();
+        if routes
+            .get(&self.id)
+            .is_some_and(|r| r.number == self.number)
+        {
+            routes.remove(&self.id);
+        }
+    }
+}
+
+/// wrong pairing codes tried before pairing stops until listening again with a new code
+const MAX_PAIR_FAILURES: u32 = 5;
+/// wrong pairing codes tried from one ip before it is refused
+const MAX_IP_PAIR_FAILURES: u32 = 2;
+/// connections being accepted at once, in total and from one ip (a session and its data streams)
+const MAX_ACCEPTING: usize = 32;
+const MAX_IP_ACCEPTING: usize = 16;
+
+/// Counts the pairings of a listener, each ip pairs one at a time.
+/// Running pairings count as failed, so there are never more guesses than allowed.
+#[derive(Default)]
+struct PairGuard {
+    failures: HashMap<IpAddr, u32>,
+    running: HashSet<IpAddr>,
+}
+
+impl PairGuard {
+    fn failed(&self) -> u32 {
+        self.failures.values().sum()
+    }
+
+    /// no more pairing with this code
+    fn stopped(&self) -> bool {
+        self.failed() >= MAX_PAIR_FAILURES
+    }
+
+    /// Start pairing with `ip`, `end` must follow.
+    fn begin(&mut self, ip: IpAddr) -> Result<(), String> {
+        if self.failed() + self.running.len() as u32 >= MAX_PAIR_FAILURES {
+            return Err(
+                "Pairing stopped after too many wrong codes, start listening again with a new code"
+                    .to_string(),
+            );
+        }
+        if self.failures.get(&ip).copied().unwrap_or_default() >= MAX_IP_PAIR_FAILURES {
+            return Err("Too many wrong pairing codes".to_string());
+        }
+        if !self.running.insert(ip) {
+            return Err("Already pairing".to_string());
+        }
+        Ok(())
+    }
+
+    /// `wrong` if the peer tried a wrong code
+    fn end(&mut self, ip: IpAddr, wrong: bool) {
+        self.running.remove(&ip);
+        if wrong {
+            *self.failures.entry(ip).or_default() += 1;
+            if self.stopped() {
+                printlnl!("[Pairing Stopped] {MAX_PAIR_FAILURES} wrong pairing codes");
+            }
+        }
+    }
+}
+
+/// Accept connections until stopped, each in its own thread. New peers go through
+/// the handshake, data streams of connected peers are routed to their session.
+fn listen(
+    l: TcpListener,
+    connected: Sender<Connected>,
+    stop: Arc<AtomicBool>,
+    code: String,
+    ip_addr: String,
+    guard: Arc<Mutex<PairGuard>>,
+) {
+    // poll, to notice the stop flag
+    if let Err(e) = l.set_nonblocking(true) {
+        let _ = connected.send(Err(e.to_string()));
+        return;
+    }
+    let routes: Routes = Arc::new(Mutex::new(HashMap::new()));
+    // connections being accepted from each ip
+    let accepting: Arc<Mutex<HashMap<IpAddr, usize>>> = Arc::new(Mutex::new(HashMap::new()));
+    while !stop.load(Ordering::SeqCst) {
+        match l.accept() {
+            Ok((s, addr)) => {
+                println!("[Accect From]: {:?}", addr);
+                let ip = addr.ip();
+                {
+                    let mut accepting = accepting.lock().unwrap();
+                    let total: usize = accepting.values().sum();
+                    let count = accepting.entry(ip).or_default();
+                    if total >= MAX_ACCEPTING || *count >= MAX_IP_ACCEPTING {
+                        println!("[Refused] {addr}: too many connections");
+                        continue;
                     }
-                    .into();
-                    match tcp_write(&mut stream, &data) {
-                        Ok(()) => {
-                            let Ok(data) = tcp_read(&mut stream) else {
-                                return (None, None, vec![]);
-                            };
-                            (None, Some(stream), data)
+                    *count += 1;
+                }
+                let connected = connected.clone();
+                let routes = routes.clone();
+                let guard = guard.clone();
+                let accepting = accepting.clone();
+                let code = code.clone();
+                let ip_addr = ip_addr.clone();
+                thread::spawn(move || {
+                    match accept_connection(s, &code, &ip_addr, &routes, &guard) {
+                        Ok(Some(c)) => {
+                            let _ = connected.send(Ok(c));
                         }
-                        Err(e) => {
-                            printlnl!("[Connect Send Error]: {e}");
-                            (None, None, vec![])
+                        Ok(None) => println!("[Data Stream From]: {addr}"),
+                        Err(e) => println!("[Refused] {addr}: {e}"),
+                    }
+                    let mut accepting = accepting.lock().unwrap();
+                    if let Some(count) = accepting.get_mut(&ip) {
+                        *count -= 1;
+                        if *count == 0 {
+                            accepting.remove(&ip);
                         }
                     }
-                }));
+                });
             }
-            ListenerState::LISTENING => {
-                match &self.handle {
-                    Some(h) if h.is_finished() => {
-                        self.state = ListenerState::ACCEPTED;
-                    }
-                    _ => return false,
-                }
-                return true;
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
             }
-            ListenerState::TOSTOP => {
-                self.handle = None;
-                self.state = ListenerState::READY;
-                self.port.0 = 0;
-                self.port.1 = 0.to_string();
+            Err(e) => {
+                printlnl!("[Accept Error]: {e}");
+                let _ = connected.send(Err(e.to_string()));
+                return;
             }
-            _ => (),
         }
-        return false;
     }
+    println!("[Listen Stop] {ip_addr}");
 }
 
-impl From<Ifv4Addr> for MyTcplistener {
-    fn from(ifv4: Ifv4Addr) -> Self {
-        let ip4 = ifv4.ip.octets();
-        let ip4 = [
-            (ip4[0], ip4[0].to_string()),
-            (ip4[1], ip4[1].to_string()),
-            (ip4[2], ip4[2].to_string()),
-            (ip4[3], ip4[3].to_string()),
-        ];
-        let port = (0, String::new());
-        Self {
-            ip4,
-            port,
-            state: ListenerState::READY,
-            name: String::new(),
-            handle: None,
+/// Return the new session, or `None` for a data stream routed to its session.
+fn accept_connection(
+    s: TcpStream,
+    code: &str,
+    ip_addr: &str,
+    routes: &Routes,
+    guard: &Mutex<PairGuard>,
+) -> Result<Option<MyConnection>, Box<dyn Error>> {
+    // some platforms pass the non-blocking mode of the listener on
+    s.set_nonblocking(false)?;
+    let ip = s.peer_addr()?.ip();
+    let mut s = MyStream::accept(s, None)?;
+    s.sock().set_read_timeout(Some(Duration::from_secs(10)))?;
+    let (frame_type, data) = read_any_frame(&mut s)?;
+    match frame_type {
+        FrameType::Join => {
+            s.sock().set_read_timeout(None)?;
+            let id = String::from_utf8(data)?;
+            let routes = routes.lock().unwrap();
+            let route = routes
+                .get(&id)
+                .ok_or("Data stream of a peer not connected")?;
+            if route.ip != ip || route.fingerprint != s.peer_fingerprint() {
+                return Err(format!("Data stream of another device than {id}").into());
+            }
+            if route.streams.send(s).is_err() {
+                return Err("Data stream of a closed session".into());
+            }
+            Ok(None)
         }
+        FrameType::Hello => {
+            let peer = handshake(&mut s, Some((guard, ip)), code, ip_addr, Some(data))?;
+            static NUMBER: AtomicUsize = AtomicUsize::new(0);
+            let number = NUMBER.fetch_add(1, Ordering::SeqCst);
+            let (streams, receiver) = mpsc::channel();
+            let route = Route {
+                ip,
+                fingerprint: s.peer_fingerprint(),
+                streams,
+                number,
+            };
+            routes.lock().unwrap().insert(peer.id.clone(), route);
+            let streams = MyStreams {
+                streams: receiver,
+                id: peer.id.clone(),
+                number,
+                routes: routes.clone(),
+            };
+            Ok(Some((Some(streams), s, peer)))
+        }
+        t => Err(format!("Unexpected {:?} frame", t).into()),
     }
 }
 
-impl Into<SocketAddr> for &MyTcplistener {
-    fn into(self) -> SocketAddr {
-        let ip =
-            std::net::Ipv4Addr::new(self.ip4[0].0, self.ip4[1].0, self.ip4[2].0, self.ip4[3].0);
-        std::net::SocketAddr::new(ip.into(), self.port.0)
+impl From<Interface> for MyTcplistener {
+    fn from(interface: Interface) -> Self {
+        let mut ls = Self::NULL.with_name(interface.name);
+        ls.ip = match &interface.addr {
+            IfAddr::V4(ifv4) => (Some(ifv4.ip.into()), ifv4.ip.to_string()),
+            IfAddr::V6(ifv6) => (Some(ifv6.ip.into()), ifv6.ip.to_string()),
+        };
+        // link-local addresses fe80::/10 need the interface to be routed
+        if let IfAddr::V6(ifv6) = &interface.addr {
+            if ifv6.ip.segments()[0] & 0xffc0 == 0xfe80 {
+                ls.scope_id = interface.index.unwrap_or_default();
+                ls.ip.1 = format!("{}%{}", ifv6.ip, ls.scope_id);
+            }
+        }
+        ls
     }
 }
-impl Into<SocketAddr> for &mut MyTcplistener {
-    fn into(self) -> SocketAddr {
-        let s: &MyTcplistener = self;
-        s.into()
+
+impl From<SocketAddr> for MyTcplistener {
+    fn from(addr: SocketAddr) -> Self {
+        let mut ls = Self::NULL;
+        ls.set_addr(&addr.to_string());
+        ls
     }
 }
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize)]
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
 pub enum TCPSignal {
     Accept {
         ip_addr: String,
+        /// device id and name, see `device::MyDevice`
+        id: String,
         name: String,
     },
     AddTcpStream,
     PostFile(FileState, crate::file::FileBlocks),
+    /// ask which blocks of the file id are still missing
+    QueryRemaining(usize),
+    /// file id, blocks still missing
+    Remaining(usize, Vec<usize>),
     Parden,
+    /// file id, index of a block received corrupt, to be sent again
+    Nack(usize, usize),
+    /// file id the receiver does not want, its sending stops
+    Reject(usize),
     Shut,
     #[default]
     ErrorInto,
+    /// pairing element of the listening side, see `pake::MySpake`
+    Challenge(Vec<u8>),
+    /// pairing element of the connecting side, and its proof of the shared key
+    Pair {
+        element: Vec<u8>,
+        proof: [u8; 32],
+    },
+    /// proof of the shared key of the listening side
+    Paired([u8; 32]),
+    /// connection refused, with the reason
+    Refuse(String),
+    /// whether the sender trusts the receiver's device, pairing is skipped if both do
+    Trusted(bool),
+    /// text copied on the peer, only sent if both have `CAP_CLIPBOARD`
+    Clipboard(String),
+    /// png of an image copied on the peer, only sent if both have `CAP_CLIPBOARD_IMAGE`
+    ClipboardImage(Vec<u8>),
+    /// file id, whether the whole file matches its digest, the sender waits for it
+    /// before the file counts as sent
+    Received(usize, bool),
 }
 
 impl TCPSignal {
     pub const AC: Self = Self::Accept {
         ip_addr: String::new(),
+        id: String::new(),
         name: String::new(),
     };
     pub fn is_ok(&self) -> bool {
@@ -292,7 +710,10 @@ impl TCPSignal {
 
 impl From<&Vec<u8>> for TCPSignal {
     fn from(bytes: &Vec<u8>) -> Self {
-        bincode::deserialize(bytes).unwrap_or_default()
+        bincode::deserialize(bytes).unwrap_or_else(|e| {
+            println!("[TCPSignal][Decode Error] {e}");
+            Self::ErrorInto
+        })
     }
 }
 impl From<Vec<u8>> for TCPSignal {
@@ -300,14 +721,14 @@ impl From<Vec<u8>> for TCPSignal {
         (&bytes).into()
     }
 }
-impl Into<Vec<u8>> for &TCPSignal {
-    fn into(self) -> Vec<u8> {
-        bincode::serialize(self).unwrap_or_default()
+impl From<&TCPSignal> for Vec<u8> {
+    fn from(signal: &TCPSignal) -> Self {
+        bincode::serialize(signal).unwrap_or_default()
     }
 }
-impl Into<Vec<u8>> for TCPSignal {
-    fn into(self) -> Vec<u8> {
-        (&self).into()
+impl From<TCPSignal> for Vec<u8> {
+    fn from(signal: TCPSignal) -> Self {
+        (&signal).into()
     }
 }
 
@@ -322,27 +743,332 @@ pub enum ListenerState {
     TODELETE = -1,
 }
 
-pub fn tcp_write(stream: &mut TcpStream, data: &Vec<u8>) -> Result<(), Box<dyn Error>> {
-    stream.write_all(&data.len().to_le_bytes())?;
+/// Every frame starts with `FRAME_MAGIC`, its `FrameType` and the length of
+/// the data as little endian u32, the same on every platform.
+const FRAME_MAGIC: [u8; 2] = *b"FN";
+const FRAME_HEADER_LEN: usize = 7;
+/// larger frames are refused before anything is allocated
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameType {
+    Hello = 1,
+    Signal = 2,
+    Block = 3,
+    /// first frame of a data stream, with the device id of the session it belongs to
+    Join = 4,
+    /// a `FileBlock` with its data deflated, see `CAP_DEFLATE`
+    DeflatedBlock = 5,
+}
+impl TryFrom<u8> for FrameType {
+    type Error = FrameError;
+    fn try_from(value: u8) -> Result<Self, FrameError> {
+        match value {
+            1 => Ok(Self::Hello),
+            2 => Ok(Self::Signal),
+            3 => Ok(Self::Block),
+            4 => Ok(Self::Join),
+            5 => Ok(Self::DeflatedBlock),
+            t => Err(FrameError::UnknownType(t)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    Io(std::io::Error),
+    /// the header does not start with `FRAME_MAGIC`, the stream is out of step
+    BadMagic([u8; 2]),
+    UnknownType(u8),
+    Unexpected {
+        expected: FrameType,
+        got: FrameType,
+    },
+    /// length over `MAX_FRAME_SIZE`
+    TooLarge(usize),
+}
+impl FrameError {
+    /// The peer sent garbage, so the stream cannot be read any more.
+    pub fn is_malformed(&self) -> bool {
+        !matches!(self, Self::Io(_))
+    }
+}
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{e}"),
+            Self::BadMagic(magic) => write!(f, "Bad frame magic {:02x?}", magic),
+            Self::UnknownType(t) => write!(f, "Unknown frame type {t}"),
+            Self::Unexpected { expected, got } => {
+                write!(f, "Expected a {:?} frame, got {:?}", expected, got)
+            }
+            Self::TooLarge(len) => {
+                write!(f, "Frame of {len} bytes over the limit {MAX_FRAME_SIZE}")
+            }
+        }
+    }
+}
+impl Error for FrameError {}
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+pub fn write_frame(
+    stream: &mut impl Write,
+    frame_type: FrameType,
+    data: &[u8],
+) -> Result<(), FrameError> {
+    if data.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(data.len()));
+    }
+    let mut header = [0; FRAME_HEADER_LEN];
+    header[..2].copy_from_slice(&FRAME_MAGIC);
+    header[2] = frame_type as u8;
+    header[3..].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    stream.write_all(&header)?;
     stream.write_all(data)?;
+    stream.flush()?;
     Ok(())
 }
 
-pub fn tcp_read(stream: &mut TcpStream) -> Result<Vec<u8>, Box<dyn Error>> {
-    const SIZE_LEN: usize = std::mem::size_of::<usize>();
-    let mut size_data = [0; SIZE_LEN];
-    stream.read_exact(&mut size_data)?;
-    let len = usize::from_le_bytes(size_data);
+pub fn read_frame(stream: &mut impl Read, expected: FrameType) -> Result<Vec<u8>, FrameError> {
+    let (got, res) = read_any_frame(stream)?;
+    if got != expected {
+        return Err(FrameError::Unexpected { expected, got });
+    }
+    Ok(res)
+}
+
+pub fn read_any_frame(stream: &mut impl Read) -> Result<(FrameType, Vec<u8>), FrameError> {
+    let mut header = [0; FRAME_HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    if header[..2] != FRAME_MAGIC {
+        return Err(FrameError::BadMagic([header[0], header[1]]));
+    }
+    let got = FrameType::try_from(header[2])?;
+    let len = u32::from_le_bytes([header[3], header[4], header[5], header[6]]) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(FrameError::TooLarge(len));
+    }
     let mut res = vec![0; len];
     stream.read_exact(&mut res)?;
-    Ok(res)
+    Ok((got, res))
+}
+
+/// write a `TCPSignal` frame
+pub fn tcp_write(stream: &mut MyStream, data: &[u8]) -> Result<(), FrameError> {
+    write_frame(stream, FrameType::Signal, data)
+}
+
+/// read a `TCPSignal` frame
+pub fn tcp_read(stream: &mut MyStream) -> Result<Vec<u8>, FrameError> {
+    read_frame(stream, FrameType::Signal)
+}
+
+/// a random 6 digit pairing code
+pub fn pair_code() -> String {
+    format!("{:06}", rand::random::<u32>() % 1_000_000)
+}
+
+/// The device at the other end of a connection
+#[derive(Debug, Clone, Default)]
+pub struct MyPeerInfo {
+    pub id: String,
+    pub name: String,
+    /// set if a trusted device came with another certificate
+    pub warning: Option<String>,
+    /// optional features both sides have, see `CAPABILITIES`
+    pub capabilities: Vec<String>,
+}
+impl MyPeerInfo {
+    pub fn has(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
+
+/// Exchange `Hello`, fail if the peer speaks another protocol version.
+/// `peer_hello` is the frame of the peer if already read.
+/// Return the capabilities both sides have.
+fn hello(
+    stream: &mut MyStream,
+    peer_hello: Option<Vec<u8>>,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let hello = Hello {
+        app: APP.to_string(),
+        version: PROTOCOL_VERSION,
+        capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+    };
+    const TOO_OLD: &str = "The peer is not file-net, or a version too old to tell its protocol";
+    write_frame(stream, FrameType::Hello, &bincode::serialize(&hello)?)?;
+    let peer: Hello = match peer_hello.map_or_else(|| read_frame(stream, FrameType::Hello), Ok) {
+        Ok(data) => bincode::deserialize(&data)
+            .ok()
+            .filter(|h: &Hello| h.app == APP)
+            .ok_or(TOO_OLD)?,
+        Err(e) if e.is_malformed() => return Err(TOO_OLD.into()),
+        Err(e) => return Err(e.into()),
+    };
+    if peer.version != PROTOCOL_VERSION {
+        return Err(format!(
+            "The peer speaks protocol version {}, this one {PROTOCOL_VERSION}, \
+             update both to the same release",
+            peer.version
+        )
+        .into());
+    }
+    Ok(peer
+        .capabilities
+        .into_iter()
+        .filter(|c| CAPABILITIES.contains(&c.as_str()))
+        .collect())
+}
+
+/// After `Hello`, the `Accept` exchange, in which both sides tell their device id and name.
+/// Unless both sides trust each other already, they have to pair with the code,
+/// and are trusted from then on.
+/// The listening side passes its `PairGuard` and the ip of the peer.
+fn handshake(
+    stream: &mut MyStream,
+    guard: Option<(&Mutex<PairGuard>, IpAddr)>,
+    code: &str,
+    ip_addr: &str,
+    peer_hello: Option<Vec<u8>>,
+) -> Result<MyPeerInfo, Box<dyn Error>> {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    stream.sock().set_read_timeout(Some(TIMEOUT))?;
+    let peer_fp = stream
+        .peer_fingerprint()
+        .ok_or("No certificate from peer")?;
+    let capabilities = hello(stream, peer_hello)?;
+    let me = device::device();
+    let accept = TCPSignal::Accept {
+        ip_addr: ip_addr.to_string(),
+        id: me.id,
+        name: me.name,
+    };
+    tcp_write(stream, &Vec::from(accept))?;
+    let (id, name) = match tcp_read(stream)?.into() {
+        TCPSignal::Accept { id, name, .. } => (id, name),
+        TCPSignal::Refuse(reason) => return Err(reason.into()),
+        e => return Err(format!("Unexpected signal {:?}", e).into()),
+    };
+    let trust = device::trust_of(&id, &peer_fp);
+    let warning = (trust == Trust::Changed)
+        .then(|| format!("The key of the trusted device {name} ({id}) has changed"));
+    if let Some(warning) = &warning {
+        println!("[Warning] {warning}");
+    }
+    tcp_write(
+        stream,
+        &Vec::from(TCPSignal::Trusted(trust == Trust::Trusted)),
+    )?;
+    let trusted = match tcp_read(stream)?.into() {
+        TCPSignal::Trusted(peer_trust) => trust == Trust::Trusted && peer_trust,
+        e => return Err(format!("Unexpected signal {:?}", e).into()),
+    };
+    if !trusted {
+        pair(stream, guard, code, &peer_fp).map_err(|e| match &warning {
+            Some(warning) => format!("{warning}: {e}").into(),
+            None => e,
+        })?;
+    }
+    device::trust(&id, &name, &peer_fp);
+    stream.sock().set_read_timeout(None)?;
+    Ok(MyPeerInfo {
+        id,
+        name,
+        warning,
+        capabilities,
+    })
+}
+
+/// Both sides agree on a key from the pairing code with SPAKE2, and prove they got
+/// the same one. Neither the code nor anything to guess it offline is sent,
+/// so a fake peer learns at most whether its one guess was right.
+fn pair(
+    stream: &mut MyStream,
+    guard: Option<(&Mutex<PairGuard>, IpAddr)>,
+    code: &str,
+    peer_fp: &Fingerprint,
+) -> Result<(), Box<dyn Error>> {
+    let host = guard.is_some();
+    // (connecting side, listening side)
+    let fps = if host {
+        (*peer_fp, tls::my_fingerprint())
+    } else {
+        (tls::my_fingerprint(), *peer_fp)
+    };
+    let spake = MySpake::new(code, host, &fps);
+    if let Some((guard, ip)) = guard {
+        if let Err(e) = guard.lock().unwrap().begin(ip) {
+            let _ = tcp_write(stream, &Vec::from(TCPSignal::Refuse(e.clone())));
+            return Err(e.into());
+        }
+        let mut wrong = false;
+        let paired = (|| -> Result<(), Box<dyn Error>> {
+            tcp_write(stream, &Vec::from(TCPSignal::Challenge(spake.element())))?;
+            let (element, proof) = match tcp_read(stream)?.into() {
+                TCPSignal::Pair { element, proof } => (element, proof),
+                TCPSignal::Refuse(reason) => return Err(reason.into()),
+                e => return Err(format!("Unexpected signal {:?}", e).into()),
+            };
+            let key = spake.finish(&element)?;
+            if proof != pake::confirm(&key, "client") {
+                wrong = true;
+                let refuse = TCPSignal::Refuse("Wrong pairing code".to_string());
+                let _ = tcp_write(stream, &Vec::from(refuse));
+                return Err("Wrong pairing code".into());
+            }
+            let proof = pake::confirm(&key, "host");
+            tcp_write(stream, &Vec::from(TCPSignal::Paired(proof)))?;
+            Ok(())
+        })();
+        guard.lock().unwrap().end(ip, wrong);
+        paired?;
+    } else {
+        let element = match tcp_read(stream)?.into() {
+            TCPSignal::Challenge(element) => element,
+            TCPSignal::Refuse(reason) => return Err(reason.into()),
+            e => return Err(format!("Unexpected signal {:?}", e).into()),
+        };
+        if code.trim().is_empty() {
+            let refuse = TCPSignal::Refuse("Pairing code needed".to_string());
+            let _ = tcp_write(stream, &Vec::from(refuse));
+            return Err("Pairing code needed".into());
+        }
+        let my_element = spake.element();
+        let key = spake.finish(&element)?;
+        let pair = TCPSignal::Pair {
+            element: my_element,
+            proof: pake::confirm(&key, "client"),
+        };
+        tcp_write(stream, &Vec::from(pair))?;
+        match tcp_read(stream)?.into() {
+            TCPSignal::Paired(proof) if proof == pake::confirm(&key, "host") => {}
+            TCPSignal::Refuse(reason) => return Err(reason.into()),
+            // the listening side does not know the code
+            _ => return Err("Peer failed the pairing code check".into()),
+        }
+    }
+    Ok(())
+}
+
+fn add_tcp_stream(peer: &str, ts: MyStream, requested: bool) -> MyCommand {
+    if requested {
+        MyCommand::AddTcpSender(peer.to_string(), ts)
+    } else {
+        MyCommand::AddTcpReceiver(peer.to_string(), ts)
+    }
 }
 
 pub fn connect_loop(
-    mut ts: TcpStream,
+    mut ts: MyStream,
     cmd_s: Sender<MyCommand>,
     sx: Receiver<MyConnectCommand>,
-    tls: Option<TcpListener>,
+    tls: Option<MyStreams>,
+    (peer, session): (String, usize),
 ) {
     let mut error_cnt = 0;
     // 0 for Nothing
@@ -354,6 +1080,10 @@ pub fn connect_loop(
     // 4 for sending {action_signal}
     let mut action: i32 = 0;
     let mut action_signal = TCPSignal::AC;
+    // whether the stream being added is requested by this side, which sends on it
+    let mut requested = false;
+    // data streams go to the paired peer, with the same certificate
+    let peer_fp = ts.peer_fingerprint();
     loop {
         if action == 0 {
             match sx.try_recv() {
@@ -364,6 +1094,7 @@ pub fn connect_loop(
                 Ok(MyConnectCommand::AddTcpStream) => {
                     println!("[Connect Loop]AddTcpStream");
                     action = 1;
+                    requested = true;
                 }
                 Ok(MyConnectCommand::TCPSignal(s)) => {
                     println!("[Connect Loop]SendTCPSignal");
@@ -388,12 +1119,13 @@ pub fn connect_loop(
                             0 => {
                                 error_cnt = 0;
                                 thread::sleep(Duration::from_millis(1000));
-                                if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+                                if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                                     println!("[Signal][Send] Error {e}");
                                 }
                             }
                             1 if tls.is_some() => {
-                                if let Err(e) = tcp_write(&mut ts, &TCPSignal::AddTcpStream.into())
+                                if let Err(e) =
+                                    tcp_write(&mut ts, &Vec::from(TCPSignal::AddTcpStream))
                                 {
                                     println!("[Signal][Request][AddTcpStream] Error {e}");
                                     error_cnt += 1;
@@ -402,7 +1134,8 @@ pub fn connect_loop(
                                 }
                             }
                             1 if tls.is_none() => {
-                                if let Err(e) = tcp_write(&mut ts, &TCPSignal::AddTcpStream.into())
+                                if let Err(e) =
+                                    tcp_write(&mut ts, &Vec::from(TCPSignal::AddTcpStream))
                                 {
                                     println!("[Signal][Request][AddTcpStream] Error {e}");
                                     error_cnt += 1;
@@ -411,15 +1144,20 @@ pub fn connect_loop(
                                 }
                             }
                             2 if tls.is_some() => {
-                                if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+                                if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                                     println!("[Signal][AddTcpStream][Reponse][Send] Error {e}");
                                     error_cnt += 1;
                                 } else {
                                     // CAUTION: ⚠️ This will block connect loop!
-                                    match tls.as_ref().unwrap().accept() {
-                                        Ok((ts, addr)) => {
-                                            println!("[Signal][AddTcpStream][Success] {:?}", addr);
-                                            cmd_s.send(MyCommand::AddTcpSender(ts)).unwrap();
+                                    // the listener checks and routes the stream here
+                                    let timeout = Duration::from_secs(10);
+                                    match tls.as_ref().unwrap().recv_timeout(timeout) {
+                                        Ok(ts) => {
+                                            println!("[Signal][AddTcpStream][Success]");
+                                            cmd_s
+                                                .send(add_tcp_stream(&peer, ts, requested))
+                                                .unwrap();
+                                            requested = false;
                                             action = 0;
                                         }
                                         Err(e) => {
@@ -430,15 +1168,28 @@ pub fn connect_loop(
                                 }
                             }
                             3 if tls.is_none() => {
-                                if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+                                if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                                     println!("[Signal][AddTcpStream][Reponse][Send] Error {e}");
                                     error_cnt += 1;
                                 } else {
                                     // CAUTION: ⚠️ This will block connect loop!
-                                    match TcpStream::connect(ts.peer_addr().unwrap()) {
+                                    let addr = ts.sock().peer_addr().unwrap();
+                                    let res = TcpStream::connect(addr)
+                                        .map_err(|e| e.into())
+                                        .and_then(|ts| MyStream::connect(ts, peer_fp))
+                                        .and_then(|mut ts| {
+                                            // tell the listener which session it is for
+                                            let id = device::device().id;
+                                            write_frame(&mut ts, FrameType::Join, id.as_bytes())?;
+                                            Ok(ts)
+                                        });
+                                    match res {
                                         Ok(ts) => {
                                             println!("[Signal][AddTcpStream][Success]");
-                                            cmd_s.send(MyCommand::AddTcpReceiver(ts)).unwrap();
+                                            cmd_s
+                                                .send(add_tcp_stream(&peer, ts, requested))
+                                                .unwrap();
+                                            requested = false;
                                             action = 0;
                                         }
                                         Err(e) => {
@@ -449,7 +1200,7 @@ pub fn connect_loop(
                                 }
                             }
                             4 => {
-                                if let Err(e) = tcp_write(&mut ts, &(&action_signal).into()) {
+                                if let Err(e) = tcp_write(&mut ts, &Vec::from(&action_signal)) {
                                     printlnl!("[Signal][Send] Error {e}");
                                     error_cnt += 1;
                                 } else {
@@ -464,13 +1215,15 @@ pub fn connect_loop(
                     TCPSignal::Parden => {
                         println!("[Signal] To send again");
                         thread::sleep(Duration::from_millis(200));
-                        if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                             println!("[Signal][Send] Error {e}");
                         }
                     }
                     TCPSignal::Shut => {
                         println!("[Signal] To close");
-                        cmd_s.send(MyCommand::ConnectLoopStop).unwrap();
+                        cmd_s
+                            .send(MyCommand::ConnectLoopStop(peer.clone(), session))
+                            .unwrap();
                     }
                     TCPSignal::ErrorInto => {
                         println!("[Signal] Error!");
@@ -480,7 +1233,8 @@ pub fn connect_loop(
                         if tls.is_some() {
                             if action == 0 {
                                 action = 1;
-                                if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+                                requested = false;
+                                if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                                     println!("[Signal][Send] Error {e}");
                                 }
                             }
@@ -488,12 +1242,12 @@ pub fn connect_loop(
                             // 如果是客户端，尝试连接到 host
                             // response ac
                             thread::sleep(Duration::from_millis(1000));
-                            if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+                            if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                                 println!("[Signal][AddTcpStream][Reply] Error {e}");
                                 error_cnt += 1;
                             } else {
                                 action = 3;
-                                if let Err(e) = tcp_write(&mut ts, &TCPSignal::AC.into()) {
+                                if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
                                     println!("[Signal][Send] Error {e}");
                                 }
                             }
@@ -501,7 +1255,66 @@ pub fn connect_loop(
                     }
                     TCPSignal::PostFile(f, id) => {
                         // printlnl!("POST file!");
-                        cmd_s.send(MyCommand::ReceiveFile(f, id)).unwrap();
+                        cmd_s
+                            .send(MyCommand::ReceiveFile(peer.clone(), f, id))
+                            .unwrap();
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
+                            println!("[Signal][Send] Error {e}");
+                        }
+                    }
+                    TCPSignal::QueryRemaining(id) => {
+                        cmd_s
+                            .send(MyCommand::QueryRemaining(peer.clone(), id))
+                            .unwrap();
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
+                            println!("[Signal][Send] Error {e}");
+                        }
+                    }
+                    TCPSignal::Remaining(id, remaining) => {
+                        cmd_s
+                            .send(MyCommand::SendRemaining(peer.clone(), id, remaining))
+                            .unwrap();
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
+                            println!("[Signal][Send] Error {e}");
+                        }
+                    }
+                    TCPSignal::Reject(id) => {
+                        cmd_s
+                            .send(MyCommand::FileRejected(peer.clone(), id))
+                            .unwrap();
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
+                            println!("[Signal][Send] Error {e}");
+                        }
+                    }
+                    TCPSignal::Received(id, intact) => {
+                        cmd_s
+                            .send(MyCommand::FileReceived(peer.clone(), id, intact))
+                            .unwrap();
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
+                            println!("[Signal][Send] Error {e}");
+                        }
+                    }
+                    TCPSignal::Clipboard(text) => {
+                        cmd_s
+                            .send(MyCommand::ReceiveClipboard(
+                                peer.clone(),
+                                MyClip::Text(text),
+                            ))
+                            .unwrap();
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
+                            println!("[Signal][Send] Error {e}");
+                        }
+                    }
+                    TCPSignal::ClipboardImage(png) => {
+                        cmd_s
+                            .send(MyCommand::ReceiveClipboard(
+                                peer.clone(),
+                                MyClip::Image(png),
+                            ))
+                            .unwrap();
+                        if let Err(e) = tcp_write(&mut ts, &Vec::from(TCPSignal::AC)) {
+                            println!("[Signal][Send] Error {e}");
+                        }
                     }
                     #[allow(unreachable_patterns)]
                     e => {
@@ -509,16 +1322,25 @@ pub fn connect_loop(
                     }
                 }
             }
+            Err(e) if e.is_malformed() => {
+                printlnl!("[Signal read Error]: {e}, stop connection...");
+                cmd_s
+                    .send(MyCommand::ConnectLoopStop(peer, session))
+                    .unwrap();
+                return;
+            }
             Err(e) => {
                 printlnl!("[Signal read Error]: {e}");
-                let data = TCPSignal::Parden.into();
+                let data = Vec::from(TCPSignal::Parden);
                 match tcp_write(&mut ts, &data) {
                     Err(e) => {
                         error_cnt += 1;
                         printlnl!("[Signal write Error][Parden]: {e}");
                         if error_cnt > 3 {
                             printlnl!("[Error] Cannot resume connection. Stop connection...");
-                            cmd_s.send(MyCommand::ConnectLoopStop).unwrap();
+                            cmd_s
+                                .send(MyCommand::ConnectLoopStop(peer, session))
+                                .unwrap();
                             return;
                         }
                         thread::sleep(Duration::from_millis(2000));
@@ -529,3 +1351,185 @@ pub fn connect_loop(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::connect::*;
+
+    /// a frame header with any magic, type and length
+    fn header(magic: &[u8; 2], frame_type: u8, len: u32) -> Cursor<Vec<u8>> {
+        let mut data = magic.to_vec();
+        data.push(frame_type);
+        data.extend_from_slice(&len.to_le_bytes());
+        Cursor::new(data)
+    }
+
+    #[test]
+    fn test_set_addr() {
+        // index of an interface, 0 if there is none of that name
+        let index = |name: &str| {
+            if_addrs::get_if_addrs()
+                .unwrap_or_default()
+                .into_iter()
+                .find(|i| i.name == name)
+                .and_then(|i| i.index)
+                .unwrap_or_default()
+        };
+        // text, valid, ip, scope id, port (4700 if the text has none)
+        let cases = [
+            ("192.168.1.2:8000", true, Some("192.168.1.2"), 0, 8000),
+            ("192.168.1.2", true, Some("192.168.1.2"), 0, 4700),
+            (
+                "[fe80::1%eth0]:80",
+                true,
+                Some("fe80::1"),
+                index("eth0"),
+                80,
+            ),
+            ("[fe80::1%lo]:80", true, Some("fe80::1"), index("lo"), 80),
+            ("[fe80::1%3]:8080", true, Some("fe80::1"), 3, 8080),
+            ("[fe80::1%3]", true, Some("fe80::1"), 3, 4700),
+            ("fe80::1%3", true, Some("fe80::1"), 3, 4700),
+            ("[fe80::1]:80", true, Some("fe80::1"), 0, 80),
+            ("[::1]", true, Some("::1"), 0, 4700),
+            ("::1", true, Some("::1"), 0, 4700),
+            ("2001:db8::2", true, Some("2001:db8::2"), 0, 4700),
+            // a scope only applies to ipv6
+            ("10.0.0.1%3:80", true, Some("10.0.0.1"), 0, 80),
+            ("build-box.local:9000", true, None, 0, 9000),
+            ("not an address", false, None, 0, 4700),
+        ];
+        for (text, valid, ip, scope_id, port) in cases {
+            let mut ls = MyTcplistener::NULL;
+            ls.port = (4700, "4700".to_string());
+            assert_eq!(ls.set_addr(text), valid, "{text}");
+            assert_eq!(ls.ip.0, ip.map(|ip| ip.parse().unwrap()), "{text}");
+            assert_eq!(ls.scope_id, scope_id, "{text}");
+            assert_eq!(ls.port.0, port, "{text}");
+        }
+
+        for (text, complete) in [
+            ("192.168.1.2", true),
+            ("build-box.local", true),
+            ("[fe80::1%eth0]", true),
+            ("[::1]", true),
+            ("", false),
+            // may go on as an ipv6
+            ("fe80", false),
+            ("fe80:", false),
+            ("fe80::1", false),
+            ("[fe80::1", false),
+        ] {
+            assert_eq!(MyTcplistener::is_complete_host(text), complete, "{text}");
+        }
+
+        let mut ls = MyTcplistener::NULL;
+        ls.set_ip(" [fe80::1%4] ");
+        assert_eq!(ls.ip.0, Some("fe80::1".parse().unwrap()));
+        assert_eq!(ls.scope_id, 4);
+        assert_eq!(ls.ip.1, "[fe80::1%4]");
+        ls.set_ip("fe80::1");
+        assert_eq!(ls.scope_id, 0);
+    }
+
+    #[test]
+    fn test_frame() {
+        let mut data = vec![];
+        write_frame(&mut data, FrameType::Block, b"block").unwrap();
+        write_frame(
+            &mut data,
+            FrameType::Signal,
+            &Vec::from(TCPSignal::AddTcpStream),
+        )
+        .unwrap();
+        assert_eq!(
+            data.len(),
+            2 * FRAME_HEADER_LEN + 5 + Vec::from(TCPSignal::AddTcpStream).len()
+        );
+
+        let mut stream = Cursor::new(data);
+        let (got, block) = read_any_frame(&mut stream).unwrap();
+        assert_eq!(got, FrameType::Block);
+        assert_eq!(block, b"block");
+        assert!(matches!(
+            TCPSignal::from(read_frame(&mut stream, FrameType::Signal).unwrap()),
+            TCPSignal::AddTcpStream
+        ));
+        assert!(matches!(
+            read_any_frame(&mut stream),
+            Err(FrameError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn test_bad_frame() {
+        // nothing follows the headers, so reading any data would fail with Io
+        let mut stream = header(b"NF", FrameType::Signal as u8, 5);
+        assert!(matches!(
+            read_any_frame(&mut stream),
+            Err(FrameError::BadMagic(magic)) if &magic == b"NF"
+        ));
+        let mut stream = header(&FRAME_MAGIC, 9, 5);
+        assert!(matches!(
+            read_any_frame(&mut stream),
+            Err(FrameError::UnknownType(9))
+        ));
+        let mut stream = header(&FRAME_MAGIC, FrameType::Block as u8, u32::MAX);
+        assert!(matches!(
+            read_any_frame(&mut stream),
+            Err(FrameError::TooLarge(len)) if len == u32::MAX as usize
+        ));
+        assert_eq!(stream.position(), FRAME_HEADER_LEN as u64);
+
+        let mut data = vec![];
+        write_frame(&mut data, FrameType::Block, b"block").unwrap();
+        assert!(matches!(
+            read_frame(&mut Cursor::new(data), FrameType::Signal),
+            Err(FrameError::Unexpected {
+                expected: FrameType::Signal,
+                got: FrameType::Block
+            })
+        ));
+
+        let mut data = vec![];
+        let big = vec![0; MAX_FRAME_SIZE + 1];
+        assert!(matches!(
+            write_frame(&mut data, FrameType::Block, &big),
+            Err(FrameError::TooLarge(_))
+        ));
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn test_pair_guard() {
+        let ip = |i: u8| IpAddr::V4(Ipv4Addr::new(10, 0, 0, i));
+        let mut guard = PairGuard::default();
+        // one pairing at a time from an ip
+        assert!(guard.begin(ip(1)).is_ok());
+        assert!(guard.begin(ip(1)).is_err());
+        guard.end(ip(1), false);
+        // an ip is refused after its wrong codes
+        for _ in 0..MAX_IP_PAIR_FAILURES {
+            guard.begin(ip(1)).unwrap();
+            guard.end(ip(1), true);
+        }
+        assert!(guard.begin(ip(1)).is_err());
+        assert!(!guard.stopped());
+        // running pairings count, no more guesses than allowed in total
+        let left = MAX_PAIR_FAILURES - MAX_IP_PAIR_FAILURES;
+        for i in 0..left as u8 {
+            guard.begin(ip(10 + i)).unwrap();
+        }
+        assert!(guard.begin(ip(2)).is_err());
+        guard.end(ip(10), false);
+        assert!(guard.begin(ip(2)).is_ok());
+        guard.end(ip(2), true);
+        for i in 1..left as u8 {
+            guard.end(ip(10 + i), true);
+        }
+        assert!(guard.stopped());
+        assert!(guard.begin(ip(3)).is_err());
+    }
+}
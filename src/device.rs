@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::{
+    file::{FileBlocks, FileState},
+    tls::{self, Fingerprint},
+};
+
+/// where the device file, the trusted peers and the certificate were kept before,
+/// relative to the working directory
+const OLD_CONFIG_DIR: &str = "./.file-net-struct/";
+/// overrides `config_dir`, to run several devices as one user
+const CONFIG_ENV: &str = "FILE_NET_CONFIG";
+const DEVICE_FILE: &str = "device.json";
+const TRUSTED_FILE: &str = "trusted_peers.json";
+
+/// This installation. Its key pair is the TLS certificate, see `tls`.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct MyDevice {
+    /// random, created on first run
+    pub id: String,
+    /// shown to other devices
+    pub name: String,
+    /// where received files are saved, `FileState::DOWNLOAD_DIR` if not chosen
+    #[serde(default)]
+    pub download_dir: Option<PathBuf>,
+    /// bytes per second of all sent files, 0 for no limit
+    #[serde(default)]
+    pub upload_limit: u64,
+    /// bytes per second of all received files, 0 for no limit
+    #[serde(default)]
+    pub download_limit: u64,
+    /// bounds of the block size of sent files in bytes, 0 for the default
+    #[serde(default)]
+    pub min_block_size: usize,
+    #[serde(default)]
+    pub max_block_size: usize,
+}
+
+/// A device paired with before, which may reconnect without the pairing code.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct TrustedPeer {
+    pub name: String,
+    /// hex of the certificate fingerprint it paired with
+    pub fingerprint: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Trust {
+    Unknown,
+    Trusted,
+    /// known device, but with another certificate
+    Changed,
+}
+
+/// The folder of this user's device file, trusted peers and certificate:
+/// `file-net` in the config folder of the platform, or `FILE_NET_CONFIG` if set.
+/// Files of the old `./.file-net-struct/` are copied over on first use,
+/// so the device keeps its id and pairings.
+pub fn config_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::env::var_os(CONFIG_ENV)
+            .map(PathBuf::from)
+            .or_else(|| platform_config_dir().map(|d| d.join("file-net")))
+            .unwrap_or_else(|| OLD_CONFIG_DIR.into());
+        let old = Path::new(OLD_CONFIG_DIR);
+        if dir != old && !dir.join(DEVICE_FILE).exists() && old.join(DEVICE_FILE).exists() {
+            println!("[Device] Copying the device files to {:?}", dir);
+            let copied = fs::create_dir_all(&dir).and_then(|_| {
+                for file in [DEVICE_FILE, TRUSTED_FILE, tls::CERT_FILE] {
+                    if old.join(file).exists() {
+                        fs::copy(old.join(file), dir.join(file))?;
+                    }
+                }
+                match fs::read(old.join(tls::KEY_FILE)) {
+                    Ok(key) => tls::write_private(&dir.join(tls::KEY_FILE), &key),
+                    Err(_) => Ok(()),
+                }
+            });
+            if let Err(e) = copied {
+                printlnl!("[Device][Error] Cannot copy the device files: {e}");
+            }
+        }
+        dir
+    })
+}
+
+fn platform_config_dir() -> Option<PathBuf> {
+    let var = |name| {
+        std::env::var_os(name)
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+    };
+    if cfg!(windows) {
+        var("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|h| h.join("Library/Application Support"))
+    } else {
+        var("XDG_CONFIG_HOME").or_else(|| var("HOME").map(|h| h.join(".config")))
+    }
+}
+
+fn this_device() -> &'static Mutex<MyDevice> {
+    static DEVICE: OnceLock<Mutex<MyDevice>> = OnceLock::new();
+    DEVICE.get_or_init(|| {
+        let path = config_dir().join(DEVICE_FILE);
+        let device = fs::read_to_string(path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_else(|| {
+                let id: u128 = rand::random();
+                let device = MyDevice {
+                    id: format!("{:032x}", id),
+                    name: default_name(),
+                    download_dir: None,
+                    upload_limit: 0,
+                    download_limit: 0,
+                    min_block_size: 0,
+                    max_block_size: 0,
+                };
+                println!("[Device] New device {} ({})", device.name, device.id);
+                save(DEVICE_FILE, &device);
+                device
+            });
+        Mutex::new(device)
+    })
+}
+
+pub fn device() -> MyDevice {
+    this_device().lock().unwrap().clone()
+}
+
+/// Rename this device, kept for the next runs.
+pub fn set_name(name: &str) {
+    let name = name.trim();
+    if name.is_empty() {
+        return;
+    }
+    let mut device = this_device().lock().unwrap();
+    device.name = name.to_string();
+    save(DEVICE_FILE, &*device);
+}
+
+/// where received files are saved
+pub fn download_dir() -> PathBuf {
+    device()
+        .download_dir
+        .unwrap_or_else(|| FileState::DOWNLOAD_DIR.into())
+}
+
+/// Save received files to `dir` on the next runs too.
+pub fn set_download_dir(dir: &Path) {
+    let mut device = this_device().lock().unwrap();
+    device.download_dir = Some(dir.to_path_buf());
+    save(DEVICE_FILE, &*device);
+}
+
+/// Keep the rate limits for the next runs.
+pub fn set_limits(upload: u64, download: u64) {
+    let mut device = this_device().lock().unwrap();
+    device.upload_limit = upload;
+    device.download_limit = download;
+    save(DEVICE_FILE, &*device);
+}
+
+/// (min, max) block size of sent files in bytes
+pub fn block_sizes() -> (usize, usize) {
+    let me = device();
+    let (min, max) = FileBlocks::DEFAULT_BLOCK_SIZES;
+    let min = if me.min_block_size == 0 {
+        min
+    } else {
+        me.min_block_size
+    };
+    let max = if me.max_block_size == 0 {
+        max
+    } else {
+        me.max_block_size
+    };
+    FileBlocks::block_size_bounds(min, max)
+}
+
+/// Keep the bounds of the block size for the next runs, 0 for the default.
+pub fn set_block_sizes(min: usize, max: usize) {
+    let mut device = this_device().lock().unwrap();
+    device.min_block_size = min;
+    device.max_block_size = max;
+    save(DEVICE_FILE, &*device);
+}
+
+/// the host name, until the user picks a name
+fn default_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|n| n.trim().to_string())
+        .filter(|n| !n.is_empty())
+        .unwrap_or_else(|| "file-net".to_string())
+}
+
+/// trusted devices by id
+pub fn trusted_peers() -> HashMap<String, TrustedPeer> {
+    fs::read_to_string(config_dir().join(TRUSTED_FILE))
+        .ok()
+        .and_then(|s| serde_json::from_str(&s).ok())
+        .unwrap_or_default()
+}
+
+pub fn trust_of(id: &str, fp: &Fingerprint) -> Trust {
+    match trusted_peers().get(id) {
+        Some(p) if p.fingerprint == tls::fingerprint_hex(fp) => Trust::Trusted,
+        Some(_) => Trust::Changed,
+        None => Trust::Unknown,
+    }
+}
+
+/// Trust the device with this certificate from now on, after pairing.
+pub fn trust(id: &str, name: &str, fp: &Fingerprint) {
+    let mut peers = trusted_peers();
+    let peer = TrustedPeer {
+        name: name.to_string(),
+        fingerprint: tls::fingerprint_hex(fp),
+    };
+    peers.insert(id.to_string(), peer);
+    save(TRUSTED_FILE, &peers);
+}
+
+/// The device has to pair again on the next connect.
+pub fn forget(id: &str) {
+    let mut peers = trusted_peers();
+    if peers.remove(id).is_some() {
+        save(TRUSTED_FILE, &peers);
+    }
+}
+
+fn save<T: serde::Serialize>(file: &str, value: &T) {
+    let saved = fs::create_dir_all(config_dir()).and_then(|_| {
+        fs::write(
+            config_dir().join(file),
+            serde_json::to_string_pretty(value).unwrap_or_default(),
+        )
+    });
+    if let Err(e) = saved {
+        printlnl!("[Device][Error] Cannot save {file}: {e}");
+    }
+}
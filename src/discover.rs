@@ -0,0 +1,258 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use if_addrs::IfAddr;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::device;
+
+/// udp port the announcements are broadcast to
+pub const DISCOVERY_PORT: u16 = 47474;
+/// link-local all-nodes group, announcements go to it on every ipv6 interface
+const MULTICAST_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+/// how often active listeners are announced
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(2);
+/// a peer not heard from for this long is removed from the list
+const PEER_TIMEOUT: Duration = Duration::from_secs(7);
+const APP: &str = "file-net";
+
+/// What is broadcast on the LAN while listening.
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct Announce {
+    /// always `APP`, to ignore packets of other programs
+    app: String,
+    /// random per process, to ignore our own announcements
+    id: u64,
+    name: String,
+    addrs: Vec<SocketAddr>,
+}
+
+/// A device found on the LAN
+#[derive(Debug, Clone)]
+pub struct MyPeer {
+    pub name: String,
+    /// addresses it is listening on
+    pub addrs: Vec<SocketAddr>,
+    last_seen: Instant,
+}
+
+/// Advertises our active listeners and collects those of other devices,
+/// by udp broadcast and ipv6 multicast on `DISCOVERY_PORT`.
+pub struct MyDiscovery {
+    id: u64,
+    /// addresses of our active listeners
+    adverts: Arc<Mutex<Vec<SocketAddr>>>,
+    peers: Arc<Mutex<HashMap<u64, MyPeer>>>,
+}
+
+impl MyDiscovery {
+    pub fn new() -> Self {
+        Self {
+            id: rand::random(),
+            adverts: Arc::new(Mutex::new(vec![])),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Start the announce and receive threads.
+    pub fn run(&self) {
+        let v4 = bind(Ipv4Addr::UNSPECIFIED.into());
+        let v6 = bind(Ipv6Addr::UNSPECIFIED.into());
+        if let Some((socket, _)) = &v4 {
+            if let Err(e) = socket.set_broadcast(true) {
+                printlnl!("[Discovery][Error] Cannot broadcast: {e}");
+            }
+        }
+        if let Some((socket, true)) = &v6 {
+            for index in v6_interfaces() {
+                let _ = socket.join_multicast_v6(&MULTICAST_V6, index);
+            }
+        }
+        for (socket, _) in v4.iter().chain(&v6).filter(|(_, receive)| *receive) {
+            if let Ok(socket) = socket.try_clone() {
+                let id = self.id;
+                let peers = self.peers.clone();
+                thread::spawn(move || Self::receive(socket, id, peers));
+            }
+        }
+        if v4.is_none() && v6.is_none() {
+            return;
+        }
+        let (v4, v6) = (v4.map(|(s, _)| s), v6.map(|(s, _)| s));
+        let adverts = self.adverts.clone();
+        let announce = Announce {
+            app: APP.to_string(),
+            id: self.id,
+            name: String::new(),
+            addrs: vec![],
+        };
+        thread::spawn(move || Self::announce(v4, v6, announce, adverts));
+    }
+
+    /// Set the addresses of our active listeners, announced from now on.
+    pub fn set_adverts(&self, addrs: Vec<SocketAddr>) {
+        *self.adverts.lock().unwrap() = addrs;
+    }
+
+    /// devices heard from recently
+    pub fn peers(&self) -> Vec<MyPeer> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, p| p.last_seen.elapsed() < PEER_TIMEOUT);
+        let mut peers: Vec<MyPeer> = peers.values().cloned().collect();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        peers
+    }
+
+    fn announce(
+        v4: Option<UdpSocket>,
+        v6: Option<UdpSocket>,
+        mut announce: Announce,
+        adverts: Arc<Mutex<Vec<SocketAddr>>>,
+    ) {
+        loop {
+            announce.addrs = adverts.lock().unwrap().clone();
+            // the device may be renamed meanwhile
+            announce.name = device::device().name;
+            if !announce.addrs.is_empty() {
+                let data = serde_json::to_vec(&announce).unwrap_or_default();
+                if let Some(socket) = &v4 {
+                    for target in broadcast_addrs() {
+                        let _ = socket.send_to(&data, (target, DISCOVERY_PORT));
+                    }
+                }
+                if let Some(socket) = &v6 {
+                    for index in v6_interfaces() {
+                        let target = SocketAddrV6::new(MULTICAST_V6, DISCOVERY_PORT, 0, index);
+                        let _ = socket.send_to(&data, target);
+                    }
+                }
+            }
+            thread::sleep(ANNOUNCE_INTERVAL);
+        }
+    }
+
+    fn receive(socket: UdpSocket, id: u64, peers: Arc<Mutex<HashMap<u64, MyPeer>>>) {
+        let mut buf = [0u8; 4096];
+        loop {
+            let (len, src) = match socket.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    printlnl!("[Discovery][Error] {e}");
+                    return;
+                }
+            };
+            let Ok(announce) = serde_json::from_slice::<Announce>(&buf[..len]) else {
+                continue;
+            };
+            if announce.app != APP || announce.id == id {
+                continue;
+            }
+            let addrs = announce
+                .addrs
+                .into_iter()
+                .filter(|a| !a.ip().is_loopback())
+                .map(|a| with_local_scope(a, src))
+                .collect();
+            peers.lock().unwrap().insert(
+                announce.id,
+                MyPeer {
+                    name: announce.name,
+                    addrs,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+    }
+}
+
+/// A socket on `DISCOVERY_PORT` of `ip`, and true as it receives.
+/// Another instance on this machine may own the port already,
+/// then it is bound to any port, and can only announce.
+fn bind(ip: IpAddr) -> Option<(UdpSocket, bool)> {
+    let socket = |port| -> std::io::Result<UdpSocket> {
+        let addr = SocketAddr::new(ip, port);
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if ip.is_ipv6() {
+            // the ipv4 socket has the port already
+            socket.set_only_v6(true)?;
+        }
+        socket.bind(&addr.into())?;
+        Ok(socket.into())
+    };
+    match socket(DISCOVERY_PORT) {
+        Ok(s) => Some((s, true)),
+        Err(e) => {
+            println!("[Discovery] Cannot receive on [{ip}]:{DISCOVERY_PORT}: {e}");
+            match socket(0) {
+                Ok(s) => Some((s, false)),
+                Err(e) => {
+                    printlnl!("[Discovery][Error] {e}");
+                    None
+                }
+            }
+        }
+    }
+}
+
+/// index of every interface with an ipv6 address
+fn v6_interfaces() -> Vec<u32> {
+    let mut indexes = vec![];
+    for i in if_addrs::get_if_addrs().unwrap_or_default() {
+        if let (IfAddr::V6(_), Some(index)) = (&i.addr, i.index) {
+            if !indexes.contains(&index) {
+                indexes.push(index);
+            }
+        }
+    }
+    indexes
+}
+
+/// the limited broadcast address and that of every ipv4 interface
+fn broadcast_addrs() -> Vec<Ipv4Addr> {
+    let mut addrs = vec![Ipv4Addr::BROADCAST];
+    for i in if_addrs::get_if_addrs().unwrap_or_default() {
+        if let IfAddr::V4(v4) = i.addr {
+            if let Some(b) = v4.broadcast.filter(|b| !addrs.contains(b)) {
+                addrs.push(b);
+            }
+        }
+    }
+    addrs
+}
+
+/// The scope id of an announced link-local ipv6 address is the sender's
+/// interface index, replace it with our interface the packet came in on,
+/// or that on the subnet of its ipv4 sender.
+fn with_local_scope(addr: SocketAddr, src: SocketAddr) -> SocketAddr {
+    let SocketAddr::V6(mut v6) = addr else {
+        return addr;
+    };
+    if v6.ip().segments()[0] & 0xffc0 != 0xfe80 {
+        return addr;
+    }
+    let src = match src {
+        SocketAddr::V4(src) => *src.ip(),
+        SocketAddr::V6(src) => {
+            v6.set_scope_id(src.scope_id());
+            return v6.into();
+        }
+    };
+    let scope = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .find(|i| match &i.addr {
+            IfAddr::V4(v4) => {
+                let mask = u32::from(v4.netmask);
+                u32::from(v4.ip) & mask == u32::from(src) & mask
+            }
+            _ => false,
+        })
+        .and_then(|i| i.index);
+    v6.set_scope_id(scope.unwrap_or_default());
+    v6.into()
+}
@@ -1,25 +1,61 @@
-use std::{collections::HashSet, error::Error, path::PathBuf};
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
 
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct FileBlocks {
     pub id: usize,
     pub block_size: usize,
     pub block_num: usize,
-    #[serde(skip)]
-    pub blocks: Vec<FileBlock>,
+    pub file_size: usize,
+    /// identity of the transfer, stays the same when the same file is sent again,
+    /// so that the receiver can resume it. Taken from `digest`, see `resume_key`.
+    pub key: u64,
+    /// sha256 of the whole file
+    pub digest: [u8; 32],
+    /// files of a folder, whose contents are sent one after another as one file.
+    /// Empty when a single file is sent.
+    pub manifest: Vec<FolderEntry>,
     #[serde(skip)]
     pub remaining: HashSet<usize>,
+    /// files which blocks are read from (set by `load`) or written to (set by `create`)
+    #[serde(skip)]
+    pub files: Vec<FilePart>,
+    /// where the remaining blocks are saved for resuming, set by `create`
+    #[serde(skip)]
+    pub resume: Option<PathBuf>,
 }
 impl FileBlocks {
+    /// how many received blocks between two saves of the resume state
+    pub const PERSIST_INTERVAL: usize = 64;
+    /// until the sender chooses one for the file, see `set_block_size`
+    pub const DEFAULT_BLOCK_SIZE: usize = 60 * 1024;
+    /// block sizes every peer accepts, a block must fit in a frame
+    pub const MIN_BLOCK_SIZE: usize = 4 * 1024;
+    pub const MAX_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+    /// (min, max) of the chosen block size if not configured
+    pub const DEFAULT_BLOCK_SIZES: (usize, usize) = (16 * 1024, 4 * 1024 * 1024);
     pub fn new(id: usize) -> Self {
         Self {
             id,
-            block_size: 60 * 1024,
+            block_size: Self::DEFAULT_BLOCK_SIZE,
             block_num: 0,
-            blocks: vec![],
+            file_size: 0,
+            key: 0,
+            digest: [0; 32],
+            manifest: vec![],
             remaining: HashSet::new(),
+            files: vec![],
+            resume: None,
         }
     }
     pub fn info(&self) -> Self {
@@ -27,54 +63,433 @@ impl FileBlocks {
             id: self.id,
             block_size: self.block_size,
             block_num: self.block_num,
-            blocks: vec![],
+            file_size: self.file_size,
+            key: self.key,
+            digest: self.digest,
+            manifest: self.manifest.clone(),
             remaining: self.remaining.clone(),
+            files: vec![],
+            resume: None,
         }
     }
     pub fn is_valid(&self) -> bool {
         self.id != 0
     }
-    pub fn init(&mut self) {
+    pub fn is_folder(&self) -> bool {
+        !self.manifest.is_empty()
+    }
+    /// Only reads the size of file here, block data is read by `get` when it is sent.
+    pub fn load(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let len = std::fs::metadata(path)?.len() as usize;
+        self.set_files(vec![(path.to_path_buf(), len)])
+    }
+    /// Walk the folder into `manifest`, its files are read as one file by `get`.
+    pub fn load_folder(&mut self, root: &Path) -> Result<(), Box<dyn Error>> {
+        let mut manifest = vec![];
+        FolderEntry::walk(root, "", &mut manifest)?;
+        let mut files = vec![];
+        for entry in manifest.iter().filter(|e| !e.is_folder) {
+            files.push((root.join(&entry.path), entry.size));
+        }
+        // an empty folder still needs an entry to be sent as a folder
+        if manifest.is_empty() {
+            manifest.push(FolderEntry {
+                path: String::new(),
+                is_folder: true,
+                size: 0,
+            });
+        }
+        self.manifest = manifest;
+        self.set_files(files)
+    }
+    fn set_files(&mut self, files: Vec<(PathBuf, usize)>) -> Result<(), Box<dyn Error>> {
+        self.files = FilePart::chain(files);
+        self.file_size = self.files.iter().map(|p| p.len).sum();
+        self.block_num = self.file_size.div_ceil(self.block_size);
+        self.digest = self.digest_files()?;
+        self.key = self.resume_key();
         self.remaining = (0..self.block_num).collect();
-        self.blocks.resize(self.block_num, FileBlock::DEFAULT);
-    }
-    pub fn load(&mut self, data: Vec<u8>) {
-        let block_num = (data.len() - 1) / self.block_size + 1;
-        self.block_num = block_num;
-        for i in 0..block_num {
-            let start = i * self.block_size;
-            let end = ((i + 1) * self.block_size).min(data.len());
-            let block = FileBlock {
-                file_id: self.id,
-                index: i,
-                data: data[start..end].to_vec(),
-            };
-            self.blocks.push(block);
+        Ok(())
+    }
+    /// The same for the same contents on every build and platform, and for a folder
+    /// the same paths too. Not the block size, which is chosen again when the file is
+    /// sent again.
+    fn resume_key(&self) -> u64 {
+        let mut hasher = Sha256::new();
+        hasher.update(self.digest);
+        hasher.update((self.file_size as u64).to_le_bytes());
+        for entry in self.manifest.iter() {
+            hasher.update(entry.path.as_bytes());
+            hasher.update([0]);
+        }
+        let hash: [u8; 32] = hasher.finalize().into();
+        u64::from_le_bytes(hash[..8].try_into().unwrap())
+    }
+    /// Split the loaded file into blocks of `size` instead, before it is posted.
+    pub fn set_block_size(&mut self, size: usize) {
+        self.block_size = size.clamp(Self::MIN_BLOCK_SIZE, Self::MAX_BLOCK_SIZE);
+        self.block_num = self.file_size.div_ceil(self.block_size);
+        self.remaining = (0..self.block_num).collect();
+    }
+    /// `min` and `max` within the block sizes every peer accepts, `max` not below `min`
+    pub fn block_size_bounds(min: usize, max: usize) -> (usize, usize) {
+        let min = min.clamp(Self::MIN_BLOCK_SIZE, Self::MAX_BLOCK_SIZE);
+        (min, max.clamp(min, Self::MAX_BLOCK_SIZE))
+    }
+    /// Create the destination file with its full size, so that blocks can be written
+    /// at their offsets in any order. A folder is created with all files in its manifest.
+    ///
+    /// If a partial file of the same transfer is found, it is reused and only
+    /// the blocks still missing are left in `remaining`.
+    pub fn create(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        // the block size is chosen by the sender
+        if !(Self::MIN_BLOCK_SIZE..=Self::MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return Err(format!("Block size {} not supported", self.block_size).into());
+        }
+        if self.block_num != self.file_size.div_ceil(self.block_size) {
+            return Err("Block number does not match the file size".into());
+        }
+        let path = path.to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut targets = vec![];
+        if self.is_folder() {
+            for entry in self.manifest.iter() {
+                let target = entry.target(&path)?;
+                if entry.is_folder {
+                    std::fs::create_dir_all(target)?;
+                } else {
+                    if let Some(parent) = target.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+                    targets.push((target, entry.size));
+                }
+            }
+        } else {
+            targets.push((path.clone(), self.file_size));
         }
-        self.remaining = (0..block_num).collect();
+        if targets.iter().map(|t| t.1).sum::<usize>() != self.file_size {
+            return Err("Manifest does not match the file size".into());
+        }
+
+        let resume = Self::resume_path(&path);
+        let remaining = self.read_resume(&targets, &resume);
+        for (target, size) in targets.iter() {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(remaining.is_none())
+                .open(target)?;
+            if remaining.is_none() {
+                file.set_len(*size as u64)?;
+            }
+        }
+        self.files = FilePart::chain(targets);
+        match remaining {
+            Some(remaining) => {
+                println!("[Resume] {:?} with {} blocks left", path, remaining.len());
+                self.remaining = remaining;
+            }
+            None => self.remaining = (0..self.block_num).collect(),
+        }
+        self.resume = Some(resume);
+        self.persist()
     }
-    pub fn get(&self, index: usize) -> Vec<u8> {
-        self.blocks.get(index).unwrap_or(&FileBlock::DEFAULT).into()
+    /// sha256 of the files, read block by block
+    fn digest_files(&self) -> Result<[u8; 32], Box<dyn Error>> {
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0; 1024 * 1024];
+        for part in self.files.iter() {
+            let mut file = File::open(&part.path)?;
+            let mut left = part.len;
+            while left > 0 {
+                let len = left.min(buf.len());
+                file.read_exact(&mut buf[..len])?;
+                hasher.update(&buf[..len]);
+                left -= len;
+            }
+        }
+        Ok(hasher.finalize().into())
+    }
+    /// Check the written file against the digest in the header.
+    pub fn verify(&self) -> Result<bool, Box<dyn Error>> {
+        Ok(self.digest_files()? == self.digest)
+    }
+    /// Mark every block missing again, when the file turns out corrupt.
+    pub fn reset(&mut self) -> Result<(), Box<dyn Error>> {
+        self.remaining = (0..self.block_num).collect();
+        self.persist()
+    }
+    /// `path`, or `name (1).ext`, `name (2).ext`... if something else is there already.
+    /// A partial file of this transfer is kept to be resumed.
+    pub fn free_path(&self, path: &Path) -> PathBuf {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        // `.bashrc` has no extension
+        let (stem, ext) = match name.rfind('.') {
+            Some(i) if i > 0 && !self.is_folder() => name.split_at(i),
+            _ => (name.as_str(), ""),
+        };
+        let mut candidate = path.to_path_buf();
+        let mut n = 0;
+        while candidate.exists() && !self.is_resumable(&candidate) {
+            n += 1;
+            candidate = path.with_file_name(format!("{stem} ({n}){ext}"));
+        }
+        candidate
+    }
+    /// whether the resume state next to `path` is of this transfer
+    fn is_resumable(&self, path: &Path) -> bool {
+        std::fs::read_to_string(Self::resume_path(path))
+            .ok()
+            .and_then(|data| serde_json::from_str::<ResumeState>(&data).ok())
+            .is_some_and(|state| state.key == self.key && state.file_size == self.file_size)
+    }
+    fn resume_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".resume");
+        name.into()
+    }
+    fn read_resume(&self, targets: &[(PathBuf, usize)], resume: &Path) -> Option<HashSet<usize>> {
+        let data = std::fs::read_to_string(resume).ok()?;
+        let state: ResumeState = serde_json::from_str(&data).ok()?;
+        if state.key != self.key || state.file_size != self.file_size || state.block_size == 0 {
+            return None;
+        }
+        for (target, size) in targets {
+            if std::fs::metadata(target).ok()?.len() as usize != *size {
+                return None;
+            }
+        }
+        Some(self.resize_remaining(state.remaining, state.block_size))
+    }
+    /// Blocks still missing, from those of `old_size` missing when the file was sent
+    /// with another block size: a block is missing if any part of it is.
+    fn resize_remaining(&self, remaining: Vec<usize>, old_size: usize) -> HashSet<usize> {
+        let mut res = HashSet::new();
+        for old in remaining {
+            let start = old.saturating_mul(old_size);
+            let end = start.saturating_add(old_size).min(self.file_size);
+            if start < end {
+                res.extend(start / self.block_size..=(end - 1) / self.block_size);
+            }
+        }
+        res
+    }
+    /// Save the remaining blocks next to the file, so the transfer can be resumed after
+    /// a disconnect or restart.
+    ///
+    /// Blocks written after the last call will be received again, which is harmless.
+    pub fn persist(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(resume) = self.resume.clone() else {
+            return Ok(());
+        };
+        let state = ResumeState {
+            key: self.key,
+            file_size: self.file_size,
+            block_size: self.block_size,
+            remaining: self.remaining.iter().copied().collect(),
+        };
+        self.sync()?;
+        std::fs::write(resume, serde_json::to_vec(&state)?)?;
+        Ok(())
+    }
+    /// Flush the files written since the last call to disk.
+    fn sync(&mut self) -> Result<(), Box<dyn Error>> {
+        for part in self.files.iter_mut().filter(|p| p.dirty) {
+            OpenOptions::new()
+                .write(true)
+                .open(&part.path)?
+                .sync_data()?;
+            part.dirty = false;
+        }
+        Ok(())
+    }
+    fn block_range(&self, index: usize) -> (usize, usize) {
+        let start = index * self.block_size;
+        let end = ((index + 1) * self.block_size).min(self.file_size);
+        (start, end)
+    }
+    pub fn get(&self, index: usize) -> Result<FileBlock, Box<dyn Error>> {
+        if self.files.is_empty() {
+            return Err("File blocks not loaded".into());
+        }
+        if index >= self.block_num {
+            return Err(format!("Block {index} out of range {}", self.block_num).into());
+        }
+        let (start, end) = self.block_range(index);
+        let mut data = vec![0; end - start];
+        for (part, range) in FilePart::overlap(&self.files, start, end) {
+            let mut file = File::open(&part.path)?;
+            file.seek(SeekFrom::Start((range.start + start - part.start) as u64))?;
+            file.read_exact(&mut data[range])?;
+        }
+        Ok(FileBlock {
+            file_id: self.id,
+            index,
+            hash: Sha256::digest(&data).into(),
+            data,
+        })
     }
     pub fn done(&mut self, index: usize) -> bool {
         self.remaining.remove(&index)
     }
-    pub fn set(&mut self, fb: FileBlock) {
+    /// Write the block into the files created by `create` at `index * block_size`.
+    pub fn set(&mut self, fb: FileBlock) -> Result<(), Box<dyn Error>> {
+        if self.files.is_empty() {
+            return Err("File blocks not created".into());
+        }
         let index = fb.index;
-        if fb.file_id == self.id && index < self.block_num {
-            self.blocks[index] = fb;
-            self.remaining.remove(&index);
+        if fb.file_id != self.id || index >= self.block_num {
+            return Err(format!(
+                "Block {}:{} not belongs to file {}",
+                fb.file_id, index, self.id
+            )
+            .into());
+        }
+        let (start, end) = self.block_range(index);
+        if fb.data.len() != end - start {
+            return Err(format!("Block {index} has wrong size {}", fb.data.len()).into());
+        }
+        for (part, range) in FilePart::overlap(&mut self.files, start, end) {
+            let mut file = OpenOptions::new().write(true).open(&part.path)?;
+            file.seek(SeekFrom::Start((range.start + start - part.start) as u64))?;
+            file.write_all(&fb.data[range])?;
+            part.dirty = true;
         }
+        self.remaining.remove(&index);
+        Ok(())
     }
     pub fn is_finished(&self) -> bool {
         self.remaining.is_empty()
     }
-    pub fn save(self, fs: &FileState) {
-        let data: Vec<u8> = self.blocks.into_iter().map(|b| b.data).flatten().collect();
-        let path = fs.get_path();
-        std::fs::write(path, data).unwrap();
+    /// Flush all written blocks to disk, and forget the resume state.
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        self.sync()?;
+        if let Some(resume) = &self.resume {
+            if resume.exists() {
+                std::fs::remove_file(resume)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// One file of `FileBlocks`, placed at `start` of all the data.
+/// It is opened for each block only, so that a folder may have any number of files.
+#[derive(Debug, Clone)]
+pub struct FilePart {
+    pub start: usize,
+    pub len: usize,
+    pub path: PathBuf,
+    /// written since the last `persist`, to be synced
+    dirty: bool,
+}
+impl FilePart {
+    fn chain(files: Vec<(PathBuf, usize)>) -> Vec<Self> {
+        let mut start = 0;
+        files
+            .into_iter()
+            .map(|(path, len)| {
+                let part = FilePart {
+                    start,
+                    len,
+                    path,
+                    dirty: false,
+                };
+                start += len;
+                part
+            })
+            .collect()
+    }
+    /// parts which overlap with `start..end`, with the range of it in `start..end`
+    fn overlap<I>(
+        parts: I,
+        start: usize,
+        end: usize,
+    ) -> impl Iterator<Item = (I::Item, std::ops::Range<usize>)>
+    where
+        I: IntoIterator,
+        I::Item: Borrow<Self>,
+    {
+        parts.into_iter().filter_map(move |part| {
+            let (from, to) = {
+                let part: &Self = part.borrow();
+                (part.start.max(start), (part.start + part.len).min(end))
+            };
+            (from < to).then(|| (part, from - start..to - start))
+        })
+    }
+}
+
+/// A file or folder inside a sent folder.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FolderEntry {
+    /// path relative to the sent folder, separated by `/`
+    pub path: String,
+    pub is_folder: bool,
+    pub size: usize,
+}
+impl FolderEntry {
+    fn walk(root: &Path, prefix: &str, res: &mut Vec<FolderEntry>) -> Result<(), Box<dyn Error>> {
+        let mut entries: Vec<_> =
+            std::fs::read_dir(root.join(prefix))?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = if prefix.is_empty() {
+                name
+            } else {
+                format!("{prefix}/{name}")
+            };
+            // links are not followed, they may point out of the folder or into a loop
+            let meta = std::fs::symlink_metadata(entry.path())?;
+            if meta.is_symlink() {
+                println!("[Folder] Skip the link {path}");
+                continue;
+            }
+            if meta.is_dir() {
+                res.push(FolderEntry {
+                    path: path.clone(),
+                    is_folder: true,
+                    size: 0,
+                });
+                Self::walk(root, &path, res)?;
+            } else {
+                res.push(FolderEntry {
+                    path,
+                    is_folder: false,
+                    size: meta.len() as usize,
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Where to write the entry under `root`, the path is from the peer so only plain
+    /// names are accepted.
+    fn target(&self, root: &Path) -> Result<PathBuf, Box<dyn Error>> {
+        let mut res = root.to_path_buf();
+        for name in self.path.split('/').filter(|n| !n.is_empty()) {
+            if name == "." || name == ".." || name.contains(['\\', ':']) {
+                return Err(format!("Invalid path in folder: {}", self.path).into());
+            }
+            res.push(name);
+        }
+        Ok(res)
     }
 }
+
+/// Saved next to a partial file as `{file}.resume`
+#[derive(Serialize, Deserialize, Debug)]
+struct ResumeState {
+    key: u64,
+    file_size: usize,
+    block_size: usize,
+    remaining: Vec<usize>,
+}
 impl Into<Vec<u8>> for &FileBlocks {
     fn into(self) -> Vec<u8> {
         bincode::serialize(self).unwrap_or_default()
@@ -84,7 +499,6 @@ impl From<&Vec<u8>> for FileBlocks {
     fn from(data: &Vec<u8>) -> Self {
         let mut res: FileBlocks = bincode::deserialize(data).unwrap_or_default();
         if res.is_valid() {
-            res.blocks.resize(res.block_num, FileBlock::DEFAULT);
             res.remaining = (0..res.block_num).collect();
         }
         res
@@ -95,17 +509,70 @@ impl From<&Vec<u8>> for FileBlocks {
 pub struct FileBlock {
     pub file_id: usize,
     pub index: usize,
+    /// sha256 of data
+    pub hash: [u8; 32],
     pub data: Vec<u8>,
 }
 impl FileBlock {
     pub const DEFAULT: Self = FileBlock {
         file_id: 0,
         index: 0,
+        hash: [0; 32],
         data: Vec::new(),
     };
     pub fn is_valid(&self) -> bool {
         self.file_id != 0
     }
+    /// whether data still matches its hash
+    pub fn is_intact(&self) -> bool {
+        <[u8; 32]>::from(Sha256::digest(&self.data)) == self.hash
+    }
+    /// The block with its data deflated, `None` if it does not get at least
+    /// `MIN_SAVING` percent smaller, like already compressed media.
+    /// The hash stays that of the data before.
+    pub fn deflated(&self) -> Option<FileBlock> {
+        // incompressible data is told by a sample, not to deflate all of it for nothing
+        if self.data.len() > DEFLATE_SAMPLE {
+            let sample = &self.data[..DEFLATE_SAMPLE];
+            if !worth_deflating(sample, &deflate(sample).ok()?) {
+                return None;
+            }
+        }
+        let data = deflate(&self.data).ok()?;
+        worth_deflating(&self.data, &data).then_some(FileBlock {
+            file_id: self.file_id,
+            index: self.index,
+            hash: self.hash,
+            data,
+        })
+    }
+    /// The block with its data inflated, refused if larger than `max` bytes.
+    pub fn inflated(mut self, max: usize) -> Result<FileBlock, Box<dyn Error>> {
+        let mut data = vec![];
+        DeflateDecoder::new(&self.data[..])
+            .take(max as u64 + 1)
+            .read_to_end(&mut data)?;
+        if data.len() > max {
+            return Err(format!("Block {} inflates over {max} bytes", self.index).into());
+        }
+        self.data = data;
+        Ok(self)
+    }
+}
+
+/// a block is sent deflated only if it gets this many percent smaller
+const MIN_SAVING: usize = 10;
+/// bytes deflated first to decide if a block is worth deflating
+const DEFLATE_SAMPLE: usize = 16 * 1024;
+
+fn deflate(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], Compression::fast());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn worth_deflating(data: &[u8], deflated: &[u8]) -> bool {
+    deflated.len() * 100 <= data.len() * (100 - MIN_SAVING)
 }
 impl Into<Vec<u8>> for &FileBlock {
     fn into(self) -> Vec<u8> {
@@ -155,19 +622,79 @@ pub struct FileState {
 }
 
 impl FileState {
+    pub const DOWNLOAD_DIR: &'static str = "./downloads";
+    /// Local file or folder to send.
+    pub fn from_path(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let meta = std::fs::metadata(path)?;
+        let name = path
+            .canonicalize()?
+            .file_name()
+            .ok_or("Path has no name")?
+            .to_string_lossy()
+            .to_string();
+        Ok(Self {
+            is_folder: meta.is_dir(),
+            is_linked: Some(path.to_path_buf()),
+            is_local: true,
+            is_synced: false,
+            name,
+        })
+    }
     pub fn get_path(&self) -> PathBuf {
+        self.get_path_in(Path::new(Self::DOWNLOAD_DIR))
+    }
+    /// Path of the file, remote files are placed in `download_dir`.
+    pub fn get_path_in(&self, download_dir: &Path) -> PathBuf {
         // if self.f.is_synced {
         // } else
         if !self.is_local {
-            PathBuf::from(format!("{}/{}", "./downloads", self.name))
+            download_dir.join(safe_name(&self.name))
         } else if let Some(path) = &self.is_linked {
             path.clone()
         } else {
             PathBuf::from(format!("{}/{}", "./.file-net", self.name))
         }
     }
-    pub fn get(&self) -> Result<Vec<u8>, Box<dyn Error>> {
-        Ok(std::fs::read(self.get_path())?)
+}
+
+/// The name from a peer as a plain file name, which cannot leave the download folder.
+pub fn safe_name(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    // windows drops trailing dots and spaces
+    let name = name.trim_end_matches(['.', ' ']);
+    if name.is_empty() {
+        "unnamed".to_string()
+    } else if is_reserved(name) {
+        format!("_{name}")
+    } else {
+        name.to_string()
+    }
+}
+
+/// device names windows reserves, with any extension
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or_default().trim_end();
+    let stem = stem.to_ascii_uppercase();
+    match stem.as_str() {
+        "CON" | "PRN" | "AUX" | "NUL" | "CONIN$" | "CONOUT$" => true,
+        _ => {
+            let n = stem
+                .strip_prefix("COM")
+                .or_else(|| stem.strip_prefix("LPT"));
+            let mut n = n.unwrap_or_default().chars();
+            let digit = n
+                .next()
+                .is_some_and(|c| c.is_ascii_digit() || "¹²³".contains(c));
+            digit && n.next().is_none()
+        }
     }
 }
 
@@ -246,6 +773,17 @@ impl FileManager {
 mod test {
     use crate::file::*;
 
+    /// a local file or folder to receive into
+    fn local(path: &Path, is_folder: bool) -> FileState {
+        FileState {
+            is_folder,
+            is_linked: Some(path.to_path_buf()),
+            is_local: true,
+            is_synced: false,
+            name: "dst".to_owned(),
+        }
+    }
+
     #[test]
     fn test() {
         let mut fm = FileManager::new();
@@ -269,6 +807,265 @@ mod test {
         println!("{:#?}", res);
     }
 
+    #[test]
+    fn test_load_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("blocks.bin");
+        let data: Vec<u8> = (0..150 * 1024).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut fb = FileBlocks::new(1);
+        fb.load(&path).unwrap();
+        assert_eq!(fb.file_size, data.len());
+        assert_eq!(fb.block_num, 3);
+        let read: Vec<u8> = (0..fb.block_num)
+            .rev()
+            .map(|i| fb.get(i).unwrap())
+            .rev()
+            .flat_map(|b| b.data)
+            .collect();
+        assert_eq!(read, data);
+        assert!(fb.get(fb.block_num).is_err());
+    }
+
+    #[test]
+    fn test_write_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let dst = dir.path().join("dst.bin");
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 241) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let mut sender = FileBlocks::new(7);
+        sender.load(&src).unwrap();
+        let header: Vec<u8> = (&sender.info()).into();
+        let mut receiver: FileBlocks = (&header).into();
+        let target = local(&dst, false);
+        receiver.create(&target.get_path()).unwrap();
+        for i in (0..sender.block_num).rev() {
+            assert!(!receiver.is_finished());
+            receiver.set(sender.get(i).unwrap()).unwrap();
+        }
+        assert!(receiver.is_finished());
+        assert!(receiver.verify().unwrap());
+        receiver.save().unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+    }
+
+    #[test]
+    fn test_block_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        std::fs::write(&src, vec![42; 1000]).unwrap();
+
+        let mut sender = FileBlocks::new(5);
+        sender.load(&src).unwrap();
+        let mut block = sender.get(0).unwrap();
+        assert!(block.is_intact());
+        block.data[10] ^= 1;
+        assert!(!block.is_intact());
+    }
+
+    #[test]
+    fn test_deflate_blocks() {
+        let text: Vec<u8> = (0..100 * 1024)
+            .flat_map(|i: u32| format!("{i},line of a log\n").into_bytes())
+            .take(100 * 1024)
+            .collect();
+        let block = FileBlock {
+            file_id: 1,
+            index: 2,
+            hash: Sha256::digest(&text).into(),
+            data: text.clone(),
+        };
+        let deflated = block.deflated().unwrap();
+        assert!(deflated.data.len() * 4 < text.len());
+        assert!(deflated.clone().inflated(text.len() - 1).is_err());
+        let inflated = deflated.inflated(text.len()).unwrap();
+        assert!(inflated.is_intact());
+        assert_eq!(inflated.data, text);
+
+        // like compressed media
+        let noise: Vec<u8> = (0..64u32)
+            .flat_map(|i| <[u8; 32]>::from(Sha256::digest(i.to_le_bytes())))
+            .collect();
+        let block = FileBlock {
+            data: noise,
+            ..FileBlock::DEFAULT
+        };
+        assert!(block.deflated().is_none());
+    }
+
+    #[test]
+    fn test_resume_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let dst = dir.path().join("dst.bin");
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 239) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+
+        let mut sender = FileBlocks::new(3);
+        sender.load(&src).unwrap();
+        let target = local(&dst, false);
+        let mut receiver = sender.info();
+        receiver.create(&target.get_path()).unwrap();
+        receiver.set(sender.get(0).unwrap()).unwrap();
+        receiver.set(sender.get(3).unwrap()).unwrap();
+        receiver.persist().unwrap();
+        drop(receiver);
+
+        // reconnect with the same transfer
+        let mut receiver = sender.info();
+        receiver.create(&target.get_path()).unwrap();
+        let mut remaining: Vec<_> = receiver.remaining.iter().copied().collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, vec![1, 2, 4]);
+        for i in remaining {
+            receiver.set(sender.get(i).unwrap()).unwrap();
+        }
+        receiver.save().unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+        assert!(!FileBlocks::resume_path(&dst).exists());
+
+        // the key is of the contents, not of when or where the file was written
+        std::fs::write(&dst, &data).unwrap();
+        let mut copy = FileBlocks::new(5);
+        copy.load(&dst).unwrap();
+        assert_eq!(copy.key, sender.key);
+        std::fs::write(&dst, &data[1..]).unwrap();
+        let mut other = FileBlocks::new(6);
+        other.load(&dst).unwrap();
+        assert_ne!(other.key, sender.key);
+    }
+
+    #[test]
+    fn test_resume_resized_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src.bin");
+        let dst = dir.path().join("dst.bin");
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 227) as u8).collect();
+        std::fs::write(&src, &data).unwrap();
+        let target = local(&dst, false);
+
+        let mut sender = FileBlocks::new(4);
+        sender.load(&src).unwrap();
+        let mut receiver = sender.info();
+        receiver.create(&target.get_path()).unwrap();
+        receiver.set(sender.get(0).unwrap()).unwrap();
+        receiver.set(sender.get(3).unwrap()).unwrap();
+        receiver.persist().unwrap();
+        drop(receiver);
+
+        // sent again with 32 KiB blocks, only those inside the received ones are kept
+        sender.set_block_size(32 * 1024);
+        assert_eq!(sender.block_num, 10);
+        let mut receiver = sender.info();
+        receiver.create(&target.get_path()).unwrap();
+        let mut remaining: Vec<_> = receiver.remaining.iter().copied().collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, vec![1, 2, 3, 4, 5, 7, 8, 9]);
+        for i in remaining {
+            receiver.set(sender.get(i).unwrap()).unwrap();
+        }
+        assert!(receiver.verify().unwrap());
+        receiver.save().unwrap();
+        assert_eq!(std::fs::read(&dst).unwrap(), data);
+
+        let mut header = sender.info();
+        header.block_size = FileBlocks::MAX_BLOCK_SIZE * 2;
+        assert!(header.create(&target.get_path()).is_err());
+    }
+
+    #[test]
+    fn test_folder_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        let dst = dir.path().join("dst");
+        std::fs::create_dir_all(src.join("sub/deep")).unwrap();
+        std::fs::create_dir_all(src.join("empty")).unwrap();
+        let big: Vec<u8> = (0..130 * 1024).map(|i| (i % 233) as u8).collect();
+        std::fs::write(src.join("a.txt"), b"hello").unwrap();
+        std::fs::write(src.join("sub/big.bin"), &big).unwrap();
+        std::fs::write(src.join("sub/deep/zero"), b"").unwrap();
+        // a link back up would be walked forever
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&src, src.join("sub/loop")).unwrap();
+
+        let mut sender = FileBlocks::new(9);
+        sender.load_folder(&src).unwrap();
+        assert!(sender.is_folder());
+        assert!(sender.manifest.iter().all(|e| !e.path.contains("loop")));
+        assert_eq!(sender.file_size, 5 + big.len());
+        let target = local(&dst, true);
+        let mut receiver = sender.info();
+        receiver.create(&target.get_path()).unwrap();
+        for i in 0..sender.block_num {
+            receiver.set(sender.get(i).unwrap()).unwrap();
+        }
+        assert!(receiver.verify().unwrap());
+        receiver.save().unwrap();
+        drop(receiver);
+        assert_eq!(std::fs::read(dst.join("a.txt")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(dst.join("sub/big.bin")).unwrap(), big);
+        assert!(std::fs::read(dst.join("sub/deep/zero")).unwrap().is_empty());
+        assert!(dst.join("empty").is_dir());
+
+        let escape = FolderEntry {
+            path: "../x".to_owned(),
+            is_folder: false,
+            size: 0,
+        };
+        assert!(escape.target(&dst).is_err());
+    }
+
+    #[test]
+    fn test_download_path() {
+        assert_eq!(safe_name("../../.bashrc"), ".bashrc");
+        assert_eq!(safe_name("..\\..\\evil.exe"), "evil.exe");
+        assert_eq!(safe_name("a:b?.txt"), "a_b_.txt");
+        assert_eq!(safe_name(".."), "unnamed");
+        assert_eq!(safe_name("CON"), "_CON");
+        assert_eq!(safe_name("nul.txt"), "_nul.txt");
+        assert_eq!(safe_name("Aux.tar.gz"), "_Aux.tar.gz");
+        assert_eq!(safe_name("com1"), "_com1");
+        assert_eq!(safe_name("LPT9.log"), "_LPT9.log");
+        assert_eq!(safe_name("prn .txt"), "_prn .txt");
+        assert_eq!(safe_name("COM²"), "_COM²");
+        assert_eq!(safe_name("console.txt"), "console.txt");
+        assert_eq!(safe_name("com10"), "com10");
+        assert_eq!(safe_name("lpt"), "lpt");
+        assert_eq!(safe_name("abÄ.txt"), "abÄ.txt");
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let remote = FileState {
+            is_folder: false,
+            is_linked: None,
+            is_local: false,
+            is_synced: false,
+            name: "../report.pdf".to_owned(),
+        };
+        let path = remote.get_path_in(dir);
+        assert_eq!(path, dir.join("report.pdf"));
+
+        let src = dir.join("src.bin");
+        std::fs::write(&src, vec![7; 1000]).unwrap();
+        let mut sender = FileBlocks::new(3);
+        sender.load(&src).unwrap();
+        let header: Vec<u8> = (&sender.info()).into();
+        let mut receiver: FileBlocks = (&header).into();
+        assert_eq!(receiver.free_path(&path), path);
+        std::fs::write(&path, b"another file").unwrap();
+        let renamed = receiver.free_path(&path);
+        assert_eq!(renamed, dir.join("report (1).pdf"));
+        // a partial file of the same transfer is resumed, not renamed again
+        receiver.create(&renamed).unwrap();
+        drop(receiver);
+        let receiver: FileBlocks = (&header).into();
+        assert_eq!(receiver.free_path(&path), renamed);
+        assert_eq!(std::fs::read(&path).unwrap(), b"another file");
+    }
+
     #[derive(Debug, Default)]
     struct MyStruct {
         data: String,
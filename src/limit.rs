@@ -0,0 +1,150 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// waits are checked again this often, so that a new rate applies at once
+const MAX_WAIT: Duration = Duration::from_millis(100);
+
+struct Bucket {
+    /// bytes per second, 0 for no limit
+    rate: u64,
+    /// bytes that may pass now, negative while paying back a block larger than that
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket limiting the bytes per second through it, with bursts of at most
+/// one second of traffic. Clones share the same bucket.
+#[derive(Clone)]
+pub struct MyRateLimit {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl MyRateLimit {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /// Change the rate, also for the transfers waiting now.
+    pub fn set_rate(&self, rate: u64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate as f64);
+    }
+
+    /// Wait until `bytes` may pass.
+    pub fn take(&self, bytes: usize) {
+        while let Some(wait) = self.try_take(bytes, Instant::now()) {
+            thread::sleep(wait.min(MAX_WAIT));
+        }
+    }
+
+    /// Let `bytes` pass at `now` unless the bucket is still paying back,
+    /// else return how long until it has.
+    fn try_take(&self, bytes: usize, now: Instant) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate == 0 {
+            return None;
+        }
+        let rate = bucket.rate as f64;
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.last = now;
+        if bucket.tokens >= 0.0 {
+            // a block may be larger than a second of traffic, it is paid back later
+            bucket.tokens -= bytes as f64;
+            return None;
+        }
+        Some(Duration::from_secs_f64(-bucket.tokens / rate))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::limit::*;
+
+    /// the limit, and the time it was created at
+    fn bucket(rate: u64) -> (MyRateLimit, Instant) {
+        let limit = MyRateLimit::new(rate);
+        let start = limit.bucket.lock().unwrap().last;
+        (limit, start)
+    }
+
+    fn secs(s: f64) -> Duration {
+        Duration::from_secs_f64(s)
+    }
+
+    #[test]
+    fn test_burst() {
+        let (limit, start) = bucket(1000);
+        // a second of traffic at once
+        assert_eq!(limit.try_take(600, start), None);
+        assert_eq!(limit.try_take(400, start), None);
+        // the bucket is empty, not in debt yet
+        assert_eq!(limit.try_take(1, start), None);
+        assert_eq!(limit.try_take(1, start), Some(secs(0.001)));
+
+        // idle time saves up one second at most, a larger block is paid back later
+        let (limit, start) = bucket(1000);
+        let later = start + secs(10.0);
+        assert_eq!(limit.try_take(2500, later), None);
+        assert_eq!(limit.try_take(1, later), Some(secs(1.5)));
+    }
+
+    #[test]
+    fn test_refill() {
+        let (limit, start) = bucket(1000);
+        assert_eq!(limit.try_take(3000, start), None);
+        assert_eq!(limit.try_take(100, start), Some(secs(2.0)));
+        assert_eq!(limit.try_take(100, start + secs(0.5)), Some(secs(1.5)));
+        assert_eq!(limit.try_take(100, start + secs(2.0)), None);
+        assert_eq!(limit.try_take(100, start + secs(2.0)), Some(secs(0.1)));
+        // 1000 bytes per second over a long run
+        let mut now = start + secs(2.0);
+        for _ in 0..100 {
+            while let Some(wait) = limit.try_take(100, now) {
+                now += wait;
+            }
+        }
+        assert_eq!(now, start + secs(12.0));
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let (limit, start) = bucket(0);
+        for _ in 0..10 {
+            assert_eq!(limit.try_take(usize::MAX, start), None);
+        }
+        // returns at once
+        limit.take(usize::MAX);
+    }
+
+    #[test]
+    fn test_set_rate() {
+        let (limit, start) = bucket(1000);
+        assert_eq!(limit.try_take(3000, start), None);
+        assert_eq!(limit.try_take(1, start), Some(secs(2.0)));
+        // the debt is paid back at the new rate
+        limit.set_rate(4000);
+        assert_eq!(limit.try_take(1, start), Some(secs(0.5)));
+        assert_eq!(limit.try_take(1, start + secs(0.5)), None);
+
+        // a lower rate also lowers the burst
+        limit.set_rate(100);
+        let later = start + secs(10.0);
+        assert_eq!(limit.try_take(150, later), None);
+        assert_eq!(limit.try_take(1, later), Some(secs(0.5)));
+
+        // waiting transfers pass at once without a limit
+        limit.set_rate(0);
+        assert_eq!(limit.try_take(1, later), None);
+    }
+}
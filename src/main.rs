@@ -6,23 +6,47 @@ macro_rules! printlnl {
 
 use std::{
     fmt::Debug,
+    path::PathBuf,
     process::exit,
     sync::mpsc::{Receiver, Sender},
 };
 
 use arboard::Clipboard;
-use command::{CommandLoop, MyCommand};
-use connect::{ListenerState, MyTcplistener};
+use clap::Parser;
+use command::{
+    CommandLoop, MyCommand, MyLimit, MyOffer, OfferAnswer, ReceiveFileErrorType, ReceiveFileOkType,
+    SendFileErrorType, SendFileOkType,
+};
+use connect::{pair_code, ListenerState, MyPeerInfo, MyTcplistener};
+use discover::MyDiscovery;
 use eframe::egui::{self, Align2, Widget};
-use file::{FileManager, FileStateExtend};
+use file::{FileBlocks, FileManager, FileStateExtend};
 use tray::MyTray;
 
+mod cli;
+mod clipboard;
 mod command;
 mod connect;
+mod device;
+mod discover;
 mod file;
+mod limit;
+mod pake;
+mod tls;
 mod tray;
+mod window;
 
 fn main() {
+    let opt = cli::Opt::parse();
+    if let Some(name) = &opt.name {
+        device::set_name(name);
+    }
+    opt.save_block_sizes();
+    let limits = opt.limits();
+    if let Some(command) = opt.command {
+        cli::run(command, opt.code, limits);
+        return;
+    }
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default(),
         renderer: eframe::Renderer::Glow,
@@ -46,6 +70,29 @@ struct MyApplication {
     /// ([127,0,0,1], port, state, name)
     listeners: Vec<MyTcplistener>,
     connector: MyTcplistener,
+    /// pairing code the listeners ask for
+    pair_code: String,
+    discovery: MyDiscovery,
+    /// device name being edited in the settings
+    device_name: String,
+    /// download folder being edited in the settings
+    download_dir: String,
+    /// connected peers, told by the command loop
+    sessions: Vec<MyPeerInfo>,
+    /// device id of the peer files are sent to
+    send_to: Option<String>,
+    /// send copied text to the peers automatically
+    clipboard_auto: bool,
+    /// files posted by peers, waiting to be accepted
+    offers: Vec<MyOffer>,
+    /// running transfers
+    transfers: Vec<MyTransfer>,
+    /// KiB/s of all sent and received files, 0 for no limit
+    upload_limit: u64,
+    download_limit: u64,
+    /// KiB, bounds of the block size of sent files
+    min_block_size: usize,
+    max_block_size: usize,
 
     is_listened: bool,
     is_connected: bool,
@@ -79,20 +126,68 @@ impl eframe::App for MyApplication {
             AppPage::Setting => self.draw_setting(ui),
             AppPage::About => self.draw_about(ui),
         });
+        if !self.offers.is_empty() {
+            egui::Window::new("Incoming files")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(Align2::CENTER_CENTER, [0.0, 0.0])
+                .show(ctx, |ui| self.draw_offers(ui));
+        }
         self.frames += 1;
 
-        match self.msg.try_recv() {
-            // no message
-            Err(std::sync::mpsc::TryRecvError::Empty) => (),
+        while let Ok(msg) = self.msg.try_recv() {
+            match msg {
+                MyMessage::Text(t) => self.info = t,
+                MyMessage::ConnectInterrupt(is_host) => {
+                    //...
+                }
+                MyMessage::Sessions(peers) => {
+                    // keep the chosen peer while it stays connected
+                    if !peers.iter().any(|p| Some(&p.id) == self.send_to.as_ref()) {
+                        self.send_to = peers.first().map(|p| p.id.clone());
+                    }
+                    self.sessions = peers;
+                }
+                MyMessage::Offers(offers) => self.offers = offers,
+                MyMessage::SendFile(id, Ok(SendFileOkType::SendProgress(p))) => {
+                    self.info = format!("Sending file {id}: {:.1}%", p * 100.0);
+                    self.track(true, id, Some(p));
+                }
+                MyMessage::SendFile(id, Ok(SendFileOkType::SendDone(stats))) => {
+                    self.info = format!("File {id} sent, {stats}");
+                    self.track(true, id, None);
+                }
+                MyMessage::SendFile(
+                    id,
+                    Err(
+                        SendFileErrorType::CannotReadFile
+                        | SendFileErrorType::Rejected
+                        | SendFileErrorType::NoStream
+                        | SendFileErrorType::ChecksumMismatch,
+                    ),
+                ) => self.track(true, id, None),
+                // the block is sent again, the transfer goes on
+                MyMessage::SendFile(id, Err(SendFileErrorType::SendError)) => {
+                    self.info = format!("Sending file {id}: a block failed, sending it again");
+                }
+                MyMessage::CannotSend(name, e) => self.info = format!("Cannot send {name}: {e}"),
+                MyMessage::ReceiveFile(id, Ok(ReceiveFileOkType::ReceiveProgress(p))) => {
+                    self.info = format!("Receiving file {id}: {:.1}%", p * 100.0);
+                    self.track(false, id, Some(p));
+                }
+                MyMessage::ReceiveFile(id, Ok(ReceiveFileOkType::ReceiveDone)) => {
+                    self.info = format!("File {id} received");
+                    self.track(false, id, None);
+                }
+                MyMessage::ReceiveFile(id, Err(e)) => {
+                    self.info = format!("Receiving file {id} failed: {:?}", e);
+                    self.track(false, id, None);
+                }
+                // ...
 
-            Ok(MyMessage::Text(t)) => self.info = t,
-            Ok(MyMessage::ConnectInterrupt(is_host)) => {
-                //...
+                // unexpected
+                e => println!("{:#?}", e),
             }
-            // ...
-
-            // unexpected
-            e => println!("{:#?}", e),
         }
 
         ctx.request_repaint_after(std::time::Duration::from_secs(2));
@@ -101,19 +196,23 @@ impl eframe::App for MyApplication {
 
 impl MyApplication {
     fn new<'a>(cc: &'a eframe::CreationContext<'a>) -> Self {
-        let wgpu::rwh::RawWindowHandle::Win32(handle) =
-            wgpu::rwh::HasWindowHandle::window_handle(&cc)
-                .unwrap()
-                .as_raw()
-        else {
-            panic!("Unsupported platform");
-        };
         let (sc, rc) = std::sync::mpsc::channel::<MyCommand>();
         let (sm, rm) = std::sync::mpsc::channel::<MyMessage>();
-        let tray = MyTray::new(sc.clone());
-        tray.run();
-        let cmd = CommandLoop::new(handle.hwnd.into(), sm, sc.clone(), rc);
+        match MyTray::new(sc.clone()) {
+            Ok(tray) => {
+                tray.run();
+            }
+            Err(e) => println!("Cannot create tray icon: {e}"),
+        }
+        let me = device::device();
+        let (min_block, max_block) = device::block_sizes();
+        let cmd = CommandLoop::new(Some(window::from_creation_context(cc)), sm, sc.clone(), rc)
+            .with_download_dir(device::download_dir())
+            .with_limits(me.upload_limit, me.download_limit)
+            .with_block_sizes(min_block, max_block);
         cmd.run();
+        let discovery = MyDiscovery::new();
+        discovery.run();
 
         Self {
             frames: 0,
@@ -123,6 +222,19 @@ impl MyApplication {
             msg: rm,
             listeners: vec![MyTcplistener::NULL],
             connector: MyTcplistener::NULL,
+            pair_code: pair_code(),
+            discovery,
+            device_name: device::device().name,
+            download_dir: device::download_dir().to_string_lossy().to_string(),
+            sessions: vec![],
+            send_to: None,
+            clipboard_auto: false,
+            offers: vec![],
+            transfers: vec![],
+            upload_limit: me.upload_limit / 1024,
+            download_limit: me.download_limit / 1024,
+            min_block_size: min_block / 1024,
+            max_block_size: max_block / 1024,
             is_listened: false,
             is_connected: false,
             page: AppPage::default(),
@@ -131,25 +243,27 @@ impl MyApplication {
         }
     }
 
-    fn detecting_all_ip4(&mut self) {
+    fn detecting_all_ip(&mut self) {
         self.listeners.clear();
         let ips = if_addrs::get_if_addrs().unwrap_or_default();
         for ip in ips.into_iter() {
-            if let if_addrs::IfAddr::V4(i) = ip.addr {
-                self.listeners
-                    .push(Into::<MyTcplistener>::into(i).with_name(ip.name));
-            }
+            self.listeners.push(ip.into());
         }
         self.listeners.push(MyTcplistener::NULL);
     }
 
     fn handle_listener(&mut self) {
         for ls in self.listeners.iter_mut() {
+            if ls.state == ListenerState::TOLISTEN {
+                ls.code = self.pair_code.clone();
+            }
             if ls.handle_listener() {
-                if let (Some(tls), Some(ts)) = ls.get_tls(true) {
+                if let (Some(streams), Some(ts)) = ls.get_tls() {
                     self.is_listened = true;
+                    self.info = Self::connected_info(ls);
+                    let peer = ls.device.clone().unwrap_or_default();
                     self.cmd_sender
-                        .send(MyCommand::AcceptListener(tls, ts))
+                        .send(MyCommand::AcceptListener(streams, ts, peer))
                         .unwrap();
                     break;
                 }
@@ -160,19 +274,70 @@ impl MyApplication {
         if self.listeners.len() == 0 {
             self.listeners.push(MyTcplistener::NULL);
         }
+        // announce the listeners still waiting for a peer
+        self.discovery.set_adverts(
+            self.listeners
+                .iter()
+                .filter(|l| l.state == ListenerState::LISTENING)
+                .filter_map(|l| l.addr())
+                .collect(),
+        );
     }
 
     fn handle_connector(&mut self) {
         if self.connector.handle_connector() {
-            if let (None, Some(ts)) = self.connector.get_tls(false) {
+            if let (None, Some(ts)) = self.connector.get_tls() {
                 self.is_connected = true;
+                self.info = Self::connected_info(&self.connector);
+                let peer = self.connector.device.clone().unwrap_or_default();
                 self.cmd_sender
-                    .send(MyCommand::AcceptConnector(ts))
+                    .send(MyCommand::AcceptConnector(ts, peer))
                     .unwrap();
+            } else {
+                self.info = format!("Cannot connect: {}", self.connector.error);
             }
         }
     }
 
+    /// Follow the progress of a transfer, `None` when it ends.
+    fn track(&mut self, is_send: bool, id: usize, progress: Option<f32>) {
+        let pos = self
+            .transfers
+            .iter()
+            .position(|t| t.is_send == is_send && t.id == id);
+        match (pos, progress) {
+            (Some(pos), Some(progress)) => self.transfers[pos].progress = progress,
+            (None, Some(progress)) => self.transfers.push(MyTransfer {
+                is_send,
+                id,
+                progress,
+                limit: 0,
+            }),
+            (Some(pos), None) => {
+                self.transfers.remove(pos);
+            }
+            (None, None) => (),
+        }
+    }
+
+    /// which device is connected, and how, for the info line
+    fn connected_info(ls: &MyTcplistener) -> String {
+        let mut info = match &ls.device {
+            Some(device) => format!("Connected to {}", device.name),
+            None => format!("Connected to {}", ls.ip.1),
+        };
+        if let Some(peer) = ls.peer {
+            info += &format!(" via {peer}");
+        }
+        if let Some(fp) = &ls.fingerprint {
+            info += &format!(", certificate {}", tls::fingerprint_text(fp));
+        }
+        if let Some(warning) = ls.device.as_ref().and_then(|d| d.warning.as_ref()) {
+            info += &format!(". Warning: {warning}!");
+        }
+        info
+    }
+
     fn draw_connect_control(&mut self, ui: &mut egui::Ui) {
         for ls in self.listeners.iter_mut() {
             ui.horizontal(|ui| {
@@ -182,10 +347,24 @@ impl MyApplication {
                     ls.state = ListenerState::TODELETE;
                 };
             });
+            // clients of this listener still connected
+            let clients: Vec<_> = ls
+                .clients
+                .iter()
+                .filter(|c| self.sessions.iter().any(|p| p.id == c.id))
+                .collect();
+            if !clients.is_empty() {
+                ui.horizontal(|ui| {
+                    ui.label("    Clients: ");
+                    for client in clients {
+                        ui.label(&client.name).on_hover_text(&client.id);
+                    }
+                });
+            }
         }
         ui.horizontal(|ui| {
             if ui.button("Auto detecting ip.").clicked() {
-                self.detecting_all_ip4();
+                self.detecting_all_ip();
             }
             if ui.button("Connecting All").clicked() {
                 for ls in self.listeners.iter_mut() {
@@ -207,65 +386,164 @@ impl MyApplication {
                 }
             }
         });
+        ui.horizontal(|ui| {
+            ui.label("Pairing code: ");
+            ui.strong(&self.pair_code);
+            // running listeners keep the code they started with
+            let listening = self
+                .listeners
+                .iter()
+                .any(|l| l.state == ListenerState::LISTENING);
+            if ui
+                .add_enabled(!listening, egui::Button::new("New code"))
+                .clicked()
+            {
+                self.pair_code = pair_code();
+            }
+            if self.listeners.iter().any(|l| l.pairing_stopped()) {
+                let color = ui.visuals().error_fg_color;
+                ui.colored_label(color, "Too many wrong codes, listen again with a new code");
+            }
+            ui.separator();
+            ui.label("Certificate: ");
+            ui.monospace(tls::fingerprint_text(&tls::my_fingerprint()));
+        });
         self.handle_listener();
         ui.separator();
         ui.horizontal(|ui| {
-            ui.label("Connect to ");
             let ls = &mut self.connector;
+            ui.label("Pairing code ");
+            egui::TextEdit::singleline(&mut ls.code)
+                .desired_width(60.0)
+                .interactive(ls.state == ListenerState::READY)
+                .show(ui);
+            ui.label("Connect to ");
             Self::draw_ip(ui, ls);
             self.handle_connector();
+            if let Some(peer) = self.connector.peer {
+                ui.label(format!("Connected via {peer}"));
+            }
         });
+        ui.separator();
+        self.draw_peers(ui);
     }
 
-    fn draw_ip(ui: &mut egui::Ui, ls: &mut MyTcplistener) {
-        const IP_SIZE: f32 = 25.0;
-        const PORT_WIDTH: f32 = 35.0;
-        let mut str_next: String = "".to_string();
-        let mut to_next: bool = false;
-        let mut sep = [".", ".", ".", ":"].into_iter();
-        for ip4 in ls.ip4.iter_mut() {
-            let response = egui::TextEdit::singleline(&mut ip4.1)
-                .desired_width(IP_SIZE)
-                .interactive(ls.state == ListenerState::READY)
-                .show(ui)
-                .response;
-            if to_next {
-                response.request_focus();
-                ip4.0 = 0;
-                ip4.1.clear();
-                to_next = false;
+    fn draw_peers(&mut self, ui: &mut egui::Ui) {
+        ui.label(format!("Connected devices ({})", self.sessions.len()));
+        for peer in self.sessions.iter() {
+            ui.label(&peer.name).on_hover_text(&peer.id);
+        }
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(
+                    !self.sessions.is_empty(),
+                    egui::Button::new("Send clipboard"),
+                )
+                .clicked()
+            {
+                self.cmd_sender
+                    .send(MyCommand::SendClipboard(None))
+                    .unwrap();
             }
-            if !str_next.is_empty() {
-                ip4.1 = str_next.clone();
+            if ui
+                .checkbox(&mut self.clipboard_auto, "Sync clipboard automatically")
+                .changed()
+            {
+                self.cmd_sender
+                    .send(MyCommand::AutoClipboard(self.clipboard_auto))
+                    .unwrap();
             }
-            if !str_next.is_empty() || response.changed() {
-                let s = ip4.1.clone();
-                let mut slice = 0;
-                while s[slice..].starts_with(|c: char| c == '.' || c == ':') {
-                    slice += 1;
-                }
-                if let Some((a, b)) = s[slice..].split_once(|c: char| c == '.' || c == ':') {
-                    if !a.is_empty() {
-                        ip4.1 = a.to_string();
-                        str_next = b.to_string();
-                        to_next = true;
+        });
+        ui.separator();
+        let peers = self.discovery.peers();
+        ui.label(format!("Nearby devices ({})", peers.len()));
+        for peer in peers {
+            ui.horizontal(|ui| {
+                ui.label(&peer.name);
+                for addr in peer.addrs {
+                    if ui
+                        .add_enabled(
+                            self.connector.state == ListenerState::READY,
+                            egui::Button::new(format!("Connect {addr}")),
+                        )
+                        .clicked()
+                    {
+                        let code = std::mem::take(&mut self.connector.code);
+                        self.connector = addr.into();
+                        self.connector.code = code;
+                        self.connector.state = ListenerState::TOLISTEN;
                     }
-                } else {
-                    str_next.clear();
                 }
-                if ip4.1.len() == 0 {
-                    ip4.0 = 0;
+            });
+        }
+    }
+
+    fn draw_offers(&mut self, ui: &mut egui::Ui) {
+        for offer in self.offers.iter() {
+            let kind = if offer.is_folder { "folder" } else { "file" };
+            ui.label(format!(
+                "{} sends the {kind} {} ({})",
+                offer.peer_name,
+                offer.name,
+                size_text(offer.size)
+            ))
+            .on_hover_text(&offer.peer);
+            ui.horizontal(|ui| {
+                let answer = if ui.button("Accept").clicked() {
+                    Some(OfferAnswer::Accept)
+                } else if ui.button("Reject").clicked() {
+                    Some(OfferAnswer::Reject)
+                } else if ui
+                    .button(format!("Accept all from {}", offer.peer_name))
+                    .clicked()
+                {
+                    Some(OfferAnswer::AcceptAll)
                 } else {
-                    let num: isize = ip4.1.parse().unwrap_or(ip4.0 as isize);
-                    ip4.0 = num.max(0).min(u8::MAX as isize) as u8;
-                    ip4.1 = ip4.0.to_string();
+                    None
+                };
+                if let Some(answer) = answer {
+                    self.cmd_sender
+                        .send(MyCommand::AnswerOffer(offer.id, answer))
+                        .unwrap();
                 }
-                if ip4.1.len() == 3 {
+            });
+            ui.separator();
+        }
+    }
+
+    fn draw_ip(ui: &mut egui::Ui, ls: &mut MyTcplistener) {
+        const IP_WIDTH: f32 = 180.0;
+        const PORT_WIDTH: f32 = 35.0;
+        let mut to_next: bool = false;
+        let mut host = ls.ip.1.clone();
+        let response = egui::TextEdit::singleline(&mut host)
+            .desired_width(IP_WIDTH)
+            .hint_text("ip / host name")
+            .interactive(ls.state == ListenerState::READY)
+            .text_color_opt(
+                (ls.ip.0.is_none() && ls.host_name().is_none())
+                    .then_some(ui.visuals().error_fg_color),
+            )
+            .show(ui)
+            .response
+            .on_hover_text("192.168.1.2, fe80::1%3, [fe80::1%eth0]:8000 or build-box.local:4000");
+        if response.changed() {
+            match host.rsplit_once(':') {
+                // a ':' typed after a whole host moves on to the port box
+                Some((ip, "")) if MyTcplistener::is_complete_host(ip) => {
+                    ls.set_ip(ip);
                     to_next = true;
                 }
+                // pasted `ip:port` is split into both boxes
+                Some((ip, port))
+                    if MyTcplistener::is_complete_host(ip) && port.parse::<u16>().is_ok() =>
+                {
+                    ls.set_addr(&host);
+                }
+                _ => ls.set_ip(&host),
             }
-            ui.label(sep.next().unwrap());
         }
+        ui.label(":");
         let response = egui::TextEdit::singleline(&mut ls.port.1)
             .desired_width(PORT_WIDTH)
             .interactive(ls.state == ListenerState::READY)
@@ -273,13 +551,8 @@ impl MyApplication {
             .response;
         if to_next {
             response.request_focus();
-            ls.port.0 = 0;
-            ls.port.1.clear();
         }
-        if !str_next.is_empty() {
-            ls.port.1 = str_next.clone();
-        }
-        if !str_next.is_empty() || response.changed() {
+        if response.changed() {
             if ls.port.1.len() == 0 {
                 ls.port.0 = 0;
             } else {
@@ -315,15 +588,7 @@ impl MyApplication {
             .clicked()
         {
             if let Ok(text) = Clipboard::new().unwrap().get_text() {
-                for i in text.split(&['.', ':']).enumerate() {
-                    if i.0 < 4 {
-                        ls.ip4[i.0].0 = i.1.parse().unwrap_or_default();
-                        ls.ip4[i.0].1 = ls.ip4[i.0].0.to_string();
-                    } else if i.0 == 4 {
-                        ls.port.0 = i.1.parse().unwrap_or_default();
-                        ls.port.1 = ls.port.0.to_string();
-                    }
-                }
+                ls.set_addr(&text);
                 println!("[Pasted]{}", ls.to_string());
             }
         }
@@ -575,7 +840,25 @@ impl MyApplication {
         self.draw_file_control_menu(ui, rows_clicked);
     }
     fn draw_file_control_menu(&mut self, ui: &mut egui::Ui, rows_clicked: Option<FileStateExtend>) {
-        if ui.button("Send").clicked() {
+        ui.horizontal(|ui| {
+            let selected = self
+                .sessions
+                .iter()
+                .find(|p| Some(&p.id) == self.send_to.as_ref())
+                .map(|p| p.name.clone())
+                .unwrap_or("No device connected".to_string());
+            ui.label("Send to ");
+            egui::ComboBox::from_id_source("send to")
+                .selected_text(selected)
+                .show_ui(ui, |ui| {
+                    for peer in self.sessions.iter() {
+                        ui.selectable_value(&mut self.send_to, Some(peer.id.clone()), &peer.name)
+                            .on_hover_text(&peer.id);
+                    }
+                });
+        });
+        let send = ui.add_enabled(self.send_to.is_some(), egui::Button::new("Send"));
+        if let (true, Some(peer)) = (send.clicked(), self.send_to.clone()) {
             let files: Vec<_> = self
                 .files
                 .current_files
@@ -589,9 +872,12 @@ impl MyApplication {
                 })
                 .collect();
             if !files.is_empty() {
-                self.cmd_sender.send(MyCommand::SendFiles(files)).unwrap();
+                self.cmd_sender
+                    .send(MyCommand::SendFiles(peer, files))
+                    .unwrap();
             }
         }
+        self.draw_transfers(ui);
         if let Some(file) = rows_clicked {
             println!("Click this row! {:?}", file);
             let pos = ui.input(|i| i.pointer.hover_pos()).unwrap_or_default();
@@ -606,7 +892,139 @@ impl MyApplication {
             ui.label("text2");
         }
     }
-    fn draw_setting(&mut self, ui: &mut egui::Ui) {}
+    fn draw_transfers(&mut self, ui: &mut egui::Ui) {
+        if self.transfers.is_empty() {
+            return;
+        }
+        ui.separator();
+        for t in self.transfers.iter_mut() {
+            ui.horizontal(|ui| {
+                let kind = if t.is_send { "Sending" } else { "Receiving" };
+                ui.label(format!("{kind} file {}", t.id));
+                ui.add(egui::ProgressBar::new(t.progress).desired_width(160.0));
+                ui.label("Limit: ");
+                let limit = ui
+                    .add(egui::DragValue::new(&mut t.limit).suffix(" KiB/s"))
+                    .on_hover_text("0 for no limit");
+                if limit.changed() {
+                    let target = if t.is_send {
+                        MyLimit::Send(t.id)
+                    } else {
+                        MyLimit::Receive(t.id)
+                    };
+                    self.cmd_sender
+                        .send(MyCommand::SetLimit(target, t.limit * 1024))
+                        .unwrap();
+                }
+            });
+        }
+    }
+    fn draw_setting(&mut self, ui: &mut egui::Ui) {
+        let me = device::device();
+        ui.heading("This device");
+        ui.horizontal(|ui| {
+            ui.label("Name: ");
+            ui.text_edit_singleline(&mut self.device_name);
+            if ui
+                .add_enabled(
+                    self.device_name.trim() != me.name,
+                    egui::Button::new("Save"),
+                )
+                .clicked()
+            {
+                device::set_name(&self.device_name);
+                self.device_name = device::device().name;
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Download folder: ");
+            let dir = device::download_dir();
+            ui.text_edit_singleline(&mut self.download_dir)
+                .on_hover_text(format!("{:?}", dir.canonicalize().unwrap_or(dir.clone())));
+            let new_dir = PathBuf::from(self.download_dir.trim());
+            if ui
+                .add_enabled(
+                    !self.download_dir.trim().is_empty() && new_dir != dir,
+                    egui::Button::new("Save"),
+                )
+                .clicked()
+            {
+                match std::fs::create_dir_all(&new_dir) {
+                    Ok(_) => {
+                        device::set_download_dir(&new_dir);
+                        self.cmd_sender
+                            .send(MyCommand::SetDownloadDir(new_dir))
+                            .unwrap();
+                    }
+                    Err(e) => self.info = format!("Cannot use {:?}: {e}", new_dir),
+                }
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Upload limit: ");
+            let upload = ui.add(egui::DragValue::new(&mut self.upload_limit).suffix(" KiB/s"));
+            ui.label("Download limit: ");
+            let download = ui.add(egui::DragValue::new(&mut self.download_limit).suffix(" KiB/s"));
+            ui.label("(0 for no limit)");
+            if upload.changed() || download.changed() {
+                let (upload, download) = (self.upload_limit * 1024, self.download_limit * 1024);
+                device::set_limits(upload, download);
+                self.cmd_sender
+                    .send(MyCommand::SetLimit(MyLimit::Upload, upload))
+                    .unwrap();
+                self.cmd_sender
+                    .send(MyCommand::SetLimit(MyLimit::Download, download))
+                    .unwrap();
+            }
+        });
+        ui.horizontal(|ui| {
+            let range = FileBlocks::MIN_BLOCK_SIZE / 1024..=FileBlocks::MAX_BLOCK_SIZE / 1024;
+            ui.label("Block size from ");
+            let min = ui.add(
+                egui::DragValue::new(&mut self.min_block_size)
+                    .clamp_range(range.clone())
+                    .suffix(" KiB"),
+            );
+            ui.label("to ");
+            let max = ui
+                .add(
+                    egui::DragValue::new(&mut self.max_block_size)
+                        .clamp_range(range)
+                        .suffix(" KiB"),
+                )
+                .on_hover_text("Chosen for each file from its size and the speed of the link");
+            if min.changed() || max.changed() {
+                self.max_block_size = self.max_block_size.max(self.min_block_size);
+                let (min, max) = (self.min_block_size * 1024, self.max_block_size * 1024);
+                device::set_block_sizes(min, max);
+                self.cmd_sender
+                    .send(MyCommand::SetBlockSizes(min, max))
+                    .unwrap();
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.label("Device id: ");
+            ui.monospace(&me.id);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Certificate: ");
+            ui.monospace(tls::fingerprint_text(&tls::my_fingerprint()));
+        });
+        ui.separator();
+        // devices paired before reconnect without the pairing code
+        let mut peers: Vec<_> = device::trusted_peers().into_iter().collect();
+        peers.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        ui.heading(format!("Trusted devices ({})", peers.len()));
+        for (id, peer) in peers {
+            ui.horizontal(|ui| {
+                ui.label(&peer.name).on_hover_text(&id);
+                ui.monospace(peer.fingerprint.get(..16).unwrap_or(&peer.fingerprint));
+                if ui.button("Forget").clicked() {
+                    device::forget(&id);
+                }
+            });
+        }
+    }
     fn draw_about(&mut self, ui: &mut egui::Ui) {}
 }
 
@@ -614,6 +1032,42 @@ impl MyApplication {
 enum MyMessage {
     Text(String),
     ConnectInterrupt(bool),
+    /// the connected peers, whenever one connects or leaves
+    Sessions(Vec<MyPeerInfo>),
+    /// files waiting to be accepted, whenever one is posted or answered
+    Offers(Vec<MyOffer>),
+    /// file id, state of sending
+    SendFile(usize, Result<SendFileOkType, SendFileErrorType>),
+    /// name and error of a file which could not be loaded, so it got no id and is not sent
+    CannotSend(String, String),
+    /// run id, state of receiving
+    ReceiveFile(usize, Result<ReceiveFileOkType, ReceiveFileErrorType>),
+}
+
+/// A running transfer, as told by the command loop
+struct MyTransfer {
+    is_send: bool,
+    /// file id when sending, run id when receiving
+    id: usize,
+    progress: f32,
+    /// KiB/s of this transfer, 0 for no limit
+    limit: u64,
+}
+
+/// `1.5 MiB`
+fn size_text(size: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < units.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{size} {}", units[unit])
+    } else {
+        format!("{size:.1} {}", units[unit])
+    }
 }
 
 impl From<String> for MyMessage {
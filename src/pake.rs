@@ -0,0 +1,86 @@
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+use crate::tls::Fingerprint;
+
+/// One side of a SPAKE2 exchange on the pairing code. Each side sends its `element`,
+/// and gets the same key from the other's only if both used the same code.
+/// Someone without the code can check a single guess per exchange,
+/// nothing seen on the wire lets them try more codes offline.
+pub struct MySpake {
+    spake: Spake2<Ed25519Group>,
+    element: Vec<u8>,
+}
+
+impl MySpake {
+    /// `fps` are the certificate fingerprints of (connecting side, listening side),
+    /// binding the key to this TLS connection.
+    pub fn new(code: &str, host: bool, fps: &(Fingerprint, Fingerprint)) -> Self {
+        let code: String = code.split_whitespace().collect();
+        let password = Password::new(code.as_bytes());
+        let (client, host_id) = (Identity::new(&fps.0), Identity::new(&fps.1));
+        let (spake, element) = if host {
+            Spake2::<Ed25519Group>::start_b(&password, &client, &host_id)
+        } else {
+            Spake2::<Ed25519Group>::start_a(&password, &client, &host_id)
+        };
+        Self { spake, element }
+    }
+
+    /// sent to the other side
+    pub fn element(&self) -> Vec<u8> {
+        self.element.clone()
+    }
+
+    /// The key shared with the side which sent `peer`,
+    /// an error if it is not a valid element.
+    pub fn finish(self, peer: &[u8]) -> Result<Vec<u8>, String> {
+        self.spake
+            .finish(peer)
+            .map_err(|e| format!("Invalid pairing element from the peer: {e:?}"))
+    }
+}
+
+/// proof of knowing the key of `MySpake::finish`, `role` tells the two directions apart
+pub fn confirm(key: &[u8], role: &str) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(role)
+        .chain_update(key)
+        .finalize()
+        .into()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pake::*;
+
+    fn keys(client_code: &str, host_code: &str) -> (Vec<u8>, Vec<u8>) {
+        let fps = ([1; 32], [2; 32]);
+        let client = MySpake::new(client_code, false, &fps);
+        let host = MySpake::new(host_code, true, &fps);
+        let (to_host, to_client) = (client.element(), host.element());
+        (
+            client.finish(&to_client).unwrap(),
+            host.finish(&to_host).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_spake() {
+        let (client, host) = keys("123 456", "123456");
+        assert_eq!(client, host);
+        assert_eq!(confirm(&client, "client"), confirm(&host, "client"));
+        assert_ne!(confirm(&client, "client"), confirm(&host, "host"));
+
+        let (client, host) = keys("123457", "123456");
+        assert_ne!(client, host);
+
+        // the element of the same side, or garbage
+        let fps = ([1; 32], [2; 32]);
+        let host = MySpake::new("123456", true, &fps);
+        let other = MySpake::new("123456", true, &fps).element();
+        assert!(host.finish(&other).is_err());
+        let host = MySpake::new("123456", true, &fps);
+        assert!(host.finish(&[0; 3]).is_err());
+    }
+}
@@ -0,0 +1,281 @@
+use std::{
+    error::Error,
+    fs,
+    io::{Read, Write},
+    net::TcpStream,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use rustls::{
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    ClientConfig, ClientConnection, DigitallySignedStruct, DistinguishedName, ServerConfig,
+    ServerConnection, SignatureScheme, StreamOwned,
+};
+use sha2::{Digest, Sha256};
+
+use crate::device;
+
+/// sha256 of a certificate
+pub type Fingerprint = [u8; 32];
+
+/// the certificate and its key, kept in `device::config_dir`
+pub const CERT_FILE: &str = "cert.der";
+pub const KEY_FILE: &str = "key.der";
+
+/// The self-signed certificate of this device, created on first run.
+struct Identity {
+    cert: CertificateDer<'static>,
+    key: PrivatePkcs8KeyDer<'static>,
+    fingerprint: Fingerprint,
+}
+
+fn identity() -> &'static Identity {
+    static IDENTITY: OnceLock<Identity> = OnceLock::new();
+    IDENTITY.get_or_init(|| {
+        let dir = device::config_dir();
+        let (cert, key) = match (fs::read(dir.join(CERT_FILE)), fs::read(dir.join(KEY_FILE))) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            _ => {
+                println!("[TLS] Creating the certificate of this device");
+                let ck = rcgen::generate_simple_self_signed(vec!["file-net".to_string()])
+                    .expect("Cannot create certificate");
+                let (cert, key) = (ck.cert.der().to_vec(), ck.signing_key.serialize_der());
+                let saved = fs::create_dir_all(dir)
+                    .and_then(|_| fs::write(dir.join(CERT_FILE), &cert))
+                    .and_then(|_| write_private(&dir.join(KEY_FILE), &key));
+                if let Err(e) = saved {
+                    printlnl!("[TLS][Error] Cannot save certificate: {e}");
+                }
+                (cert, key)
+            }
+        };
+        Identity {
+            fingerprint: fingerprint(&cert),
+            cert: cert.into(),
+            key: key.into(),
+        }
+    })
+}
+
+/// Write a file only the user can read, like ssh keys.
+pub fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(data)
+}
+
+pub fn fingerprint(cert: &[u8]) -> Fingerprint {
+    Sha256::digest(cert).into()
+}
+
+/// fingerprint of this device's certificate
+pub fn my_fingerprint() -> Fingerprint {
+    identity().fingerprint
+}
+
+/// lowercase hex, as stored in files
+pub fn fingerprint_hex(fp: &Fingerprint) -> String {
+    fp.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// `ab12 cd34 ...` for people to compare
+pub fn fingerprint_text(fp: &Fingerprint) -> String {
+    fp.chunks(2)
+        .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A TLS stream over TCP, from either side.
+#[derive(Debug)]
+pub enum MyStream {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl MyStream {
+    /// Connect as TLS client. Certificates are self-signed, so instead of
+    /// a CA the server's one must match `expected` if given.
+    pub fn connect(sock: TcpStream, expected: Option<Fingerprint>) -> Result<Self, Box<dyn Error>> {
+        let config = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(PinVerifier { expected }))
+            .with_client_auth_cert(vec![identity().cert.clone()], key())?;
+        let conn = ClientConnection::new(Arc::new(config), ServerName::try_from("file-net")?)?;
+        let mut stream = Self::Client(StreamOwned::new(conn, sock));
+        stream.complete_handshake()?;
+        Ok(stream)
+    }
+
+    /// Accept as TLS server. The client must present a certificate,
+    /// which must match `expected` if given.
+    pub fn accept(sock: TcpStream, expected: Option<Fingerprint>) -> Result<Self, Box<dyn Error>> {
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()?
+            .with_client_cert_verifier(Arc::new(PinVerifier { expected }))
+            .with_single_cert(vec![identity().cert.clone()], key())?;
+        let conn = ServerConnection::new(Arc::new(config))?;
+        let mut stream = Self::Server(StreamOwned::new(conn, sock));
+        stream.complete_handshake()?;
+        Ok(stream)
+    }
+
+    fn complete_handshake(&mut self) -> Result<(), Box<dyn Error>> {
+        match self {
+            Self::Client(s) => {
+                while s.conn.is_handshaking() {
+                    s.conn.complete_io(&mut s.sock)?;
+                }
+            }
+            Self::Server(s) => {
+                while s.conn.is_handshaking() {
+                    s.conn.complete_io(&mut s.sock)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sock(&self) -> &TcpStream {
+        match self {
+            Self::Client(s) => &s.sock,
+            Self::Server(s) => &s.sock,
+        }
+    }
+
+    /// fingerprint of the certificate the peer presented
+    pub fn peer_fingerprint(&self) -> Option<Fingerprint> {
+        let certs = match self {
+            Self::Client(s) => s.conn.peer_certificates(),
+            Self::Server(s) => s.conn.peer_certificates(),
+        };
+        certs?.first().map(|c| fingerprint(c))
+    }
+}
+
+impl Read for MyStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Client(s) => s.read(buf),
+            Self::Server(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for MyStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Client(s) => s.write(buf),
+            Self::Server(s) => s.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Client(s) => s.flush(),
+            Self::Server(s) => s.flush(),
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn key() -> PrivateKeyDer<'static> {
+    PrivateKeyDer::Pkcs8(identity().key.clone_key())
+}
+
+/// Accept any self-signed certificate, or only the one with the `expected` fingerprint.
+#[derive(Debug)]
+struct PinVerifier {
+    expected: Option<Fingerprint>,
+}
+
+impl PinVerifier {
+    fn check(&self, cert: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        match self.expected {
+            Some(fp) if fp != fingerprint(cert) => Err(rustls::Error::General(
+                "Certificate does not match the pinned one".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = &provider().signature_verification_algorithms;
+        verify_tls12_signature(message, cert, dss, algs)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        let algs = &provider().signature_verification_algorithms;
+        verify_tls13_signature(message, cert, dss, algs)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        provider()
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ClientCertVerifier for PinVerifier {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        ServerCertVerifier::verify_tls12_signature(self, message, cert, dss)
+    }
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        ServerCertVerifier::verify_tls13_signature(self, message, cert, dss)
+    }
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        ServerCertVerifier::supported_verify_schemes(self)
+    }
+}
@@ -1,4 +1,4 @@
-use std::{process::exit, sync::mpsc::{Receiver, Sender}, thread::{self, JoinHandle}};
+use std::{error::Error, process::exit, sync::mpsc::{Receiver, Sender}, thread::{self, JoinHandle}};
 
 use trayicon::{Icon, MenuBuilder, MenuItem, TrayIcon, TrayIconBuilder};
 
@@ -32,16 +32,16 @@ pub struct MyTray {
     rx: Receiver<TrayEvents>,
 }
 impl MyTray {
-    pub fn new(sc: Sender<MyCommand>) -> Self {
+    pub fn new(sc: Sender<MyCommand>) -> Result<Self, Box<dyn Error>> {
         let (sx, rx) = std::sync::mpsc::channel::<TrayEvents>();
         let icon = vec![
             include_bytes!("../assets/icon.ico"),
             include_bytes!("../assets/icon.ico"),
         ];
-        let icons: Vec<Icon> = icon
+        let icons = icon
             .into_iter()
-            .map(|i| Icon::from_buffer(i, None, None).unwrap())
-            .collect();
+            .map(|i| Icon::from_buffer(i, None, None))
+            .collect::<Result<Vec<Icon>, _>>()?;
         let tray = TrayIconBuilder::new()
             .sender(move |e: &TrayEvents| {
                 // let _ = proxy.send_event(e.clone());
@@ -83,14 +83,13 @@ impl MyTray {
                     .separator()
                     .item("Exit", TrayEvents::Exit),
             )
-            .build()
-            .unwrap();
-        Self {
+            .build()?;
+        Ok(Self {
             tray,
             icons,
             cmd_sender: sc,
             rx,
-        }
+        })
     }
 
     pub fn run(mut self) -> JoinHandle<()> {
@@ -100,11 +99,11 @@ impl MyTray {
                     TrayEvents::Exit => {
                         exit(0);
                     }
-                    TrayEvents::RightClickTrayIcon => {
-                        self.tray.show_menu().unwrap();
-                    }
-                    TrayEvents::LeftClickTrayIcon => {
-                        self.tray.show_menu().unwrap();
+                    TrayEvents::RightClickTrayIcon | TrayEvents::LeftClickTrayIcon => {
+                        // KDE/StatusNotifierItem hosts show the menu themselves
+                        if let Err(e) = self.tray.show_menu() {
+                            println!("Cannot show tray menu: {e}");
+                        }
                     }
                     TrayEvents::HideWindow => {
                         self.cmd_sender.send(MyCommand::TrayHide).unwrap();
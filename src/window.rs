@@ -0,0 +1,90 @@
+use eframe::egui::{self, ViewportCommand};
+
+/// Shows and hides the window on each platform, called by the CommandLoop from its own thread
+pub trait MyWindow: Send {
+    fn show(&self);
+    fn hide(&self);
+}
+
+/// Based on eframe viewport commands, works on X11/Wayland and macOS.
+pub struct ViewportWindow {
+    ctx: egui::Context,
+    /// minimize instead of hiding, Wayland cannot hide a window
+    minimize: bool,
+}
+
+impl ViewportWindow {
+    pub fn new(ctx: egui::Context) -> Self {
+        Self {
+            ctx,
+            minimize: false,
+        }
+    }
+
+    pub fn with_minimize(mut self, minimize: bool) -> Self {
+        self.minimize = minimize;
+        self
+    }
+}
+
+impl MyWindow for ViewportWindow {
+    fn show(&self) {
+        if self.minimize {
+            self.ctx
+                .send_viewport_cmd(ViewportCommand::Minimized(false));
+        } else {
+            self.ctx.send_viewport_cmd(ViewportCommand::Visible(true));
+        }
+        self.ctx.send_viewport_cmd(ViewportCommand::Focus);
+    }
+    fn hide(&self) {
+        if self.minimize {
+            self.ctx.send_viewport_cmd(ViewportCommand::Minimized(true));
+        } else {
+            self.ctx.send_viewport_cmd(ViewportCommand::Visible(false));
+        }
+    }
+}
+
+/// On Windows a hidden winit window gets no more redraws,
+/// so the viewport command to show it again would never be processed.
+/// Call ShowWindow on the HWND directly instead.
+#[cfg(windows)]
+pub struct Win32Window {
+    hwnd: isize,
+}
+
+#[cfg(windows)]
+impl MyWindow for Win32Window {
+    fn show(&self) {
+        unsafe {
+            windows_sys::Win32::UI::WindowsAndMessaging::ShowWindow(
+                self.hwnd as _,
+                windows_sys::Win32::UI::WindowsAndMessaging::SW_SHOW,
+            );
+        }
+    }
+    fn hide(&self) {
+        unsafe {
+            windows_sys::Win32::UI::WindowsAndMessaging::ShowWindow(
+                self.hwnd as _,
+                windows_sys::Win32::UI::WindowsAndMessaging::SW_HIDE,
+            );
+        }
+    }
+}
+
+/// Pick the implementation for the window eframe just created.
+pub fn from_creation_context(cc: &eframe::CreationContext<'_>) -> Box<dyn MyWindow> {
+    #[cfg(windows)]
+    if let Ok(handle) = wgpu::rwh::HasWindowHandle::window_handle(cc) {
+        if let wgpu::rwh::RawWindowHandle::Win32(handle) = handle.as_raw() {
+            return Box::new(Win32Window {
+                hwnd: handle.hwnd.into(),
+            });
+        }
+    }
+    let wayland = wgpu::rwh::HasWindowHandle::window_handle(cc)
+        .is_ok_and(|h| matches!(h.as_raw(), wgpu::rwh::RawWindowHandle::Wayland(_)));
+    Box::new(ViewportWindow::new(cc.egui_ctx.clone()).with_minimize(wayland))
+}